@@ -80,7 +80,7 @@
 /* Set configUSE_PREEMPTION to 1 to use pre-emptive scheduling.  Set
  * configUSE_PREEMPTION to 0 to use co-operative scheduling.
  * See https://www.freertos.org/single-core-amp-smp-rtos-scheduling.html. */
-#define configUSE_PREEMPTION                       0
+#define configUSE_PREEMPTION                       1
 
 /* Set configUSE_TIME_SLICING to 1 to have the scheduler switch between Ready
  * state tasks of equal priority on every tick interrupt.  Set
@@ -646,8 +646,20 @@
 #define INCLUDE_xTaskResumeFromISR             1
 
 
-#define configMTIME_BASE_ADDRESS 0
-#define configMTIMECMP_BASE_ADDRESS 0
+/* ruvm32 CLINT, same layout as mini-rv32ima and SiFive parts. */
+#define configMTIME_BASE_ADDRESS    ( 0x1100BFF8UL )
+#define configMTIMECMP_BASE_ADDRESS ( 0x11004000UL )
+
+/* Host calls of the demo. They use the custom-0 opcode rather than ecall, so
+ * they never collide with the port, which takes every ecall as a yield. funct3
+ * selects the call and rs1 holds its argument. */
+#define configUVM32_CALL_TRACE_TICK    0
+
+/* Reports that task `task` ticked, with custom-0 (opcode 0x0b). */
+#define uvm32TRACE_TICK( task )                                          \
+    __asm volatile ( ".insn r 0x0b, %0, 0, zero, %1, zero"               \
+                     :                                                   \
+                     : "i" ( configUVM32_CALL_TRACE_TICK ), "r" ( task ) )
 
 
 #endif /* FREERTOS_CONFIG_H */
@@ -9,20 +9,6 @@
 // #include <stdio.h>
 
 
-void vPortSetupTimerInterrupt(void);
-
-
-void vPortSetupTimerInterrupt()
-{
-    // not implemented
-    __asm__ volatile (
-            "li a7, 67\n\t"   // llamada al sistema 64, write
-            "ecall\n\t"
-        );
-}
-
-
-
 /*-----------------------------------------------------------*/
 
 static void exampleTask( void * parameters );
@@ -38,12 +24,8 @@ static void exampleTask( void * parameters )
     for( ; ; )
     {
         /* Example Task Code */
-        //vTaskDelay( 100 ); /* delay 100 ticks */
-        /*__asm__ volatile (
-            "li a7, 64\n\t"   // llamada al sistema 64, write
-            "ecall\n\t"
-        );*/
-        taskYIELD();
+        vTaskDelay( 100 ); /* delay 100 ticks, woken by the CLINT tick */
+        uvm32TRACE_TICK( 1 );
     }
 }
 
@@ -55,12 +37,8 @@ static void exampleTask2( void * parameters )
     for( ; ; )
     {
         /* Example Task Code */
-        //vTaskDelay( 100 ); /* delay 100 ticks */
-        /*__asm__ volatile (
-            "li a7, 65\n\t"   // llamada al sistema 64, write
-            "ecall\n\t"
-        );*/
-        taskYIELD();
+        vTaskDelay( 100 ); /* delay 100 ticks, woken by the CLINT tick */
+        uvm32TRACE_TICK( 2 );
     }
 }
 /*-----------------------------------------------------------*/
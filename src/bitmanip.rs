@@ -0,0 +1,64 @@
+// Zba, Zbb and Zbs bit-manipulation instructions (RV32 encodings). They share
+// the OP and OP-IMM opcodes with the base ALU instructions and are told apart
+// by funct7, or by the whole immediate for the unary Zbb instructions.
+
+/// Executes `ir` if it is an instruction of one of the enabled extensions.
+/// `rs2` is the sign-extended immediate for OP-IMM. Returns `None` for any
+/// other encoding, which is left to the base decoder.
+pub fn execute(ir: u32, rs1: u32, rs2: u32, zba: bool, zbb: bool, zbs: bool) -> Option<u32> {
+    let funct3 = (ir >> 12) & 7;
+    let funct7 = ir >> 25;
+    let shamt = rs2 & 0x1f;
+    let bit = 1u32 << shamt;
+
+    if ir & 0x20 != 0 {
+        // OP
+        match (funct7, funct3) {
+            (0x10, 2) if zba => Some((rs1 << 1).wrapping_add(rs2)), // SH1ADD
+            (0x10, 4) if zba => Some((rs1 << 2).wrapping_add(rs2)), // SH2ADD
+            (0x10, 6) if zba => Some((rs1 << 3).wrapping_add(rs2)), // SH3ADD
+            (0x20, 7) if zbb => Some(rs1 & !rs2),                   // ANDN
+            (0x20, 6) if zbb => Some(rs1 | !rs2),                   // ORN
+            (0x20, 4) if zbb => Some(!(rs1 ^ rs2)),                 // XNOR
+            (0x05, 4) if zbb => Some((rs1 as i32).min(rs2 as i32) as u32), // MIN
+            (0x05, 5) if zbb => Some(rs1.min(rs2)),                 // MINU
+            (0x05, 6) if zbb => Some((rs1 as i32).max(rs2 as i32) as u32), // MAX
+            (0x05, 7) if zbb => Some(rs1.max(rs2)),                 // MAXU
+            (0x04, 4) if zbb && (ir >> 20) & 0x1f == 0 => Some(rs1 & 0xffff), // ZEXT.H
+            (0x30, 1) if zbb => Some(rs1.rotate_left(shamt)),       // ROL
+            (0x30, 5) if zbb => Some(rs1.rotate_right(shamt)),      // ROR
+            (0x24, 1) if zbs => Some(rs1 & !bit),                   // BCLR
+            (0x24, 5) if zbs => Some((rs1 >> shamt) & 1),           // BEXT
+            (0x34, 1) if zbs => Some(rs1 ^ bit),                    // BINV
+            (0x14, 1) if zbs => Some(rs1 | bit),                    // BSET
+            _ => None,
+        }
+    } else {
+        // OP-IMM
+        match (funct3, ir >> 20) {
+            (1, 0x600) if zbb => Some(rs1.leading_zeros()), // CLZ
+            (1, 0x601) if zbb => Some(rs1.trailing_zeros()), // CTZ
+            (1, 0x602) if zbb => Some(rs1.count_ones()),    // CPOP
+            (1, 0x604) if zbb => Some(rs1 as i8 as u32),    // SEXT.B
+            (1, 0x605) if zbb => Some(rs1 as i16 as u32),   // SEXT.H
+            (5, 0x287) if zbb => Some(orc_b(rs1)),          // ORC.B
+            (5, 0x698) if zbb => Some(rs1.swap_bytes()),    // REV8
+            _ => match (funct7, funct3) {
+                (0x30, 5) if zbb => Some(rs1.rotate_right(shamt)), // RORI
+                (0x24, 1) if zbs => Some(rs1 & !bit),              // BCLRI
+                (0x24, 5) if zbs => Some((rs1 >> shamt) & 1),      // BEXTI
+                (0x34, 1) if zbs => Some(rs1 ^ bit),               // BINVI
+                (0x14, 1) if zbs => Some(rs1 | bit),               // BSETI
+                _ => None,
+            },
+        }
+    }
+}
+
+// Each byte becomes 0xff if any of its bits is set, 0 otherwise.
+fn orc_b(val: u32) -> u32 {
+    (0..4).fold(0, |acc, byte| {
+        let mask = 0xff << (byte * 8);
+        if val & mask != 0 { acc | mask } else { acc }
+    })
+}
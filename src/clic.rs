@@ -0,0 +1,269 @@
+// CLIC (Core-Local Interrupt Controller) following the RISC-V Smclic draft,
+// for M-mode only harts. Each interrupt has its own pending and enable bits,
+// a trigger type and a control byte holding its level and priority. The hart
+// takes the highest-ranked pending interrupt when its level is above the
+// current one, so handlers can be preempted by more urgent interrupts.
+//
+// Interrupts 3, 7 and 11 are the CLINT software and timer interrupts and the
+// PLIC external interrupt, the others are driven by the host.
+
+pub const CLIC_BASE: u32 = 0x02800000;
+pub const CLIC_SIZE: u32 = 0x5000;
+
+/// Number of interrupts, `clicinfo.num_interrupt`.
+pub const CLIC_INTERRUPTS: u32 = 64;
+// All 8 bits of clicintctl are implemented.
+const CLICINTCTLBITS: u32 = 8;
+const CLIC_VERSION: u32 = 0x09;
+
+const CLICCFG: u32 = 0x0000;
+const CLICINFO: u32 = 0x0004;
+const CLICINT: u32 = 0x1000;
+
+// clicintattr fields. The mode field is hardwired to M-mode.
+const ATTR_SHV: u8 = 1 << 0;
+const ATTR_EDGE: u8 = 1 << 1;
+const ATTR_NEGATIVE: u8 = 1 << 2;
+const ATTR_WRITABLE: u8 = ATTR_SHV | ATTR_EDGE | ATTR_NEGATIVE;
+const ATTR_MODE_M: u8 = 3 << 6;
+
+#[derive(Clone, Copy)]
+pub struct Clic {
+    base: u32,
+    /// cliccfg.nlbits: how many clicintctl bits hold the level.
+    nlbits: u8,
+    /// Line levels set by the host, one bit per interrupt.
+    lines: u64,
+    /// Pending bits of the edge-triggered interrupts.
+    edge_ip: u64,
+    ie: u64,
+    attr: [u8; CLIC_INTERRUPTS as usize],
+    ctl: [u8; CLIC_INTERRUPTS as usize],
+}
+
+impl Default for Clic {
+    fn default() -> Self {
+        Self::new(CLIC_BASE)
+    }
+}
+
+impl Clic {
+    pub fn new(base: u32) -> Self {
+        Self {
+            base,
+            nlbits: 0,
+            lines: 0,
+            edge_ip: 0,
+            ie: 0,
+            attr: [0; CLIC_INTERRUPTS as usize],
+            ctl: [0; CLIC_INTERRUPTS as usize],
+        }
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < CLIC_SIZE
+    }
+
+    // Whether the input of interrupt `id` is asserted, after its polarity.
+    fn active(&self, id: u32) -> bool {
+        let level = self.lines & (1 << id) != 0;
+        level != (self.attr[id as usize] & ATTR_NEGATIVE != 0)
+    }
+
+    fn is_edge(&self, id: u32) -> bool {
+        self.attr[id as usize] & ATTR_EDGE != 0
+    }
+
+    /// Drives the input of interrupt `id` (0..CLIC_INTERRUPTS) high or low.
+    /// Edge-triggered interrupts become pending on the active edge.
+    pub fn set_level(&mut self, id: u32, level: bool) {
+        assert!(id < CLIC_INTERRUPTS, "CLIC interrupt {} out of range", id);
+        let was_active = self.active(id);
+        if level {
+            self.lines |= 1 << id;
+        } else {
+            self.lines &= !(1 << id);
+        }
+        if self.is_edge(id) && !was_active && self.active(id) {
+            self.edge_ip |= 1 << id;
+        }
+    }
+
+    /// clicintip of every interrupt, one bit per interrupt.
+    pub fn get_pending(&self) -> u64 {
+        (0..CLIC_INTERRUPTS)
+            .filter(|&id| self.pending(id))
+            .fold(0, |acc, id| acc | 1 << id)
+    }
+
+    fn pending(&self, id: u32) -> bool {
+        if self.is_edge(id) {
+            self.edge_ip & (1 << id) != 0
+        } else {
+            self.active(id)
+        }
+    }
+
+    /// The level of interrupt `id`: the top `nlbits` bits of its clicintctl,
+    /// with the bits below set. With no level bits every interrupt is at 255.
+    pub fn interrupt_level(&self, id: u32) -> u8 {
+        let priority_bits = 0xffu8.checked_shr(self.nlbits as u32).unwrap_or(0);
+        self.ctl[id as usize] | priority_bits
+    }
+
+    /// Whether interrupt `id` is selectively hardware vectored through mtvt.
+    pub fn is_vectored(&self, id: u32) -> bool {
+        self.attr[id as usize] & ATTR_SHV != 0
+    }
+
+    /// The highest-ranked pending and enabled interrupt. Interrupts rank by
+    /// clicintctl, so by level and then priority, and then by number.
+    pub fn highest(&self) -> Option<u32> {
+        (0..CLIC_INTERRUPTS)
+            .filter(|&id| self.ie & (1 << id) != 0 && self.pending(id))
+            .max_by_key(|&id| (self.ctl[id as usize], id))
+    }
+
+    /// Marks interrupt `id` as taken, which clears it if it is edge-triggered.
+    pub fn acknowledge(&mut self, id: u32) {
+        if self.is_edge(id) {
+            self.edge_ip &= !(1 << id);
+        }
+    }
+
+    fn read_byte(&self, offset: u32) -> u8 {
+        match offset {
+            CLICCFG => self.nlbits,
+            CLICINFO..=0x0007 => {
+                let info = CLIC_INTERRUPTS | CLIC_VERSION << 13 | CLICINTCTLBITS << 21;
+                (info >> (8 * (offset - CLICINFO))) as u8
+            }
+            _ => {
+                let Some(index) = offset.checked_sub(CLICINT) else {
+                    return 0; // Reserved, reads as zero.
+                };
+                let id = index / 4;
+                if id >= CLIC_INTERRUPTS {
+                    return 0;
+                }
+                match index % 4 {
+                    0 => self.pending(id) as u8,
+                    1 => (self.ie >> id) as u8 & 1,
+                    2 => self.attr[id as usize] | ATTR_MODE_M,
+                    _ => self.ctl[id as usize],
+                }
+            }
+        }
+    }
+
+    fn write_byte(&mut self, offset: u32, val: u8) {
+        match offset {
+            // nmbits is hardwired to zero, there is only M-mode.
+            CLICCFG => self.nlbits = (val & 0xf).min(CLICINTCTLBITS as u8),
+            _ => {
+                let Some(index) = offset.checked_sub(CLICINT) else {
+                    return; // clicinfo is read-only, the rest is reserved.
+                };
+                let id = index / 4;
+                if id >= CLIC_INTERRUPTS {
+                    return;
+                }
+                let bit = 1 << id;
+                match index % 4 {
+                    // Only edge-triggered interrupts can be set or cleared,
+                    // the others follow their input.
+                    0 => {
+                        if self.is_edge(id) {
+                            self.edge_ip = (self.edge_ip & !bit) | (val as u64 & 1) << id;
+                        }
+                    }
+                    1 => self.ie = (self.ie & !bit) | (val as u64 & 1) << id,
+                    2 => self.attr[id as usize] = val & ATTR_WRITABLE,
+                    _ => self.ctl[id as usize] = val,
+                }
+            }
+        }
+    }
+
+    /// Reads the `size`-byte register at `addr`. Registers are byte-sized, so
+    /// any naturally aligned access works. Returns `None` for misaligned ones.
+    pub fn load(&self, addr: u32, size: u32) -> Option<u32> {
+        let offset = addr.wrapping_sub(self.base);
+        if !offset.is_multiple_of(size) {
+            return None;
+        }
+        Some((0..size).fold(0, |acc, i| {
+            acc | (self.read_byte(offset + i) as u32) << (8 * i)
+        }))
+    }
+
+    /// Writes the low `size` bytes of `val` at `addr`. Returns `false` for
+    /// misaligned accesses.
+    pub fn store(&mut self, addr: u32, size: u32, val: u32) -> bool {
+        let offset = addr.wrapping_sub(self.base);
+        if !offset.is_multiple_of(size) {
+            return false;
+        }
+        for i in 0..size {
+            self.write_byte(offset + i, (val >> (8 * i)) as u8);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int_reg(id: u32, reg: u32) -> u32 {
+        CLIC_BASE + CLICINT + id * 4 + reg
+    }
+
+    // Enables interrupt `id` with control byte `ctl` and raises its line.
+    fn raise(clic: &mut Clic, id: u32, ctl: u8) {
+        clic.store(int_reg(id, 1), 1, 1);
+        clic.store(int_reg(id, 3), 1, ctl as u32);
+        clic.set_level(id, true);
+    }
+
+    #[test]
+    fn level_and_priority_arbitration() {
+        let mut clic = Clic::default();
+        clic.store(CLIC_BASE + CLICCFG, 1, 2);
+        // Two level bits: levels 0x3f, 0x7f, 0xbf and 0xff.
+        raise(&mut clic, 16, 0x40);
+        raise(&mut clic, 17, 0x7f);
+        assert_eq!(clic.interrupt_level(16), 0x7f);
+        assert_eq!(clic.interrupt_level(17), 0x7f);
+        // Same level, the higher priority wins.
+        assert_eq!(clic.highest(), Some(17));
+        raise(&mut clic, 18, 0x80);
+        assert_eq!(clic.interrupt_level(18), 0xbf);
+        assert_eq!(clic.highest(), Some(18));
+        // Same control byte, the higher number wins.
+        raise(&mut clic, 19, 0x80);
+        assert_eq!(clic.highest(), Some(19));
+        // Disabled interrupts don't take part.
+        clic.store(int_reg(19, 1), 1, 0);
+        clic.store(int_reg(18, 1), 1, 0);
+        assert_eq!(clic.highest(), Some(17));
+        assert_eq!(clic.get_pending(), 0xf << 16);
+    }
+
+    #[test]
+    fn edge_triggered_interrupts_latch() {
+        let mut clic = Clic::default();
+        clic.store(int_reg(20, 2), 1, ATTR_EDGE as u32);
+        raise(&mut clic, 20, 0xff);
+        clic.set_level(20, false);
+        assert_eq!(clic.highest(), Some(20));
+        clic.acknowledge(20);
+        assert_eq!(clic.highest(), None);
+        // Level-triggered ones follow their input.
+        raise(&mut clic, 21, 0xff);
+        clic.acknowledge(21);
+        assert_eq!(clic.highest(), Some(21));
+        clic.set_level(21, false);
+        assert_eq!(clic.highest(), None);
+    }
+}
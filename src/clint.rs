@@ -0,0 +1,89 @@
+// CLINT (Core-Local Interruptor) with the SiFive register layout, as used by
+// mini-rv32ima and the FreeRTOS RISC-V port. There is a single hart, so only
+// the hart 0 msip and mtimecmp registers exist.
+
+pub const CLINT_BASE: u32 = 0x11000000;
+pub const CLINT_SIZE: u32 = 0x10000;
+
+const MSIP: u32 = 0x0000;
+const MTIMECMP_LO: u32 = 0x4000;
+const MTIMECMP_HI: u32 = 0x4004;
+const MTIME_LO: u32 = 0xbff8;
+const MTIME_HI: u32 = 0xbffc;
+
+#[derive(Clone, Copy)]
+pub struct Clint {
+    base: u32,
+    msip: bool,
+    mtimecmp: u64,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new(CLINT_BASE)
+    }
+}
+
+impl Clint {
+    pub fn new(base: u32) -> Self {
+        Self {
+            base,
+            msip: false,
+            // Keep the timer quiet until the guest programs a deadline.
+            mtimecmp: u64::MAX,
+        }
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < CLINT_SIZE
+    }
+
+    /// Reads the 32-bit register at `addr`. `mtime` is the hart's current time.
+    /// Returns `None` for misaligned accesses.
+    pub fn load(&self, addr: u32, mtime: u64) -> Option<u32> {
+        let offset = addr.wrapping_sub(self.base);
+        if offset & 3 != 0 {
+            return None;
+        }
+        Some(match offset {
+            MSIP => self.msip as u32,
+            MTIMECMP_LO => self.mtimecmp as u32,
+            MTIMECMP_HI => (self.mtimecmp >> 32) as u32,
+            MTIME_LO => mtime as u32,
+            MTIME_HI => (mtime >> 32) as u32,
+            _ => 0, // Reserved, reads as zero.
+        })
+    }
+
+    /// Writes the 32-bit register at `addr`. A write to mtime updates `mtime`
+    /// in place. Returns `false` for misaligned accesses.
+    pub fn store(&mut self, addr: u32, val: u32, mtime: &mut u64) -> bool {
+        let offset = addr.wrapping_sub(self.base);
+        if offset & 3 != 0 {
+            return false;
+        }
+        match offset {
+            MSIP => self.msip = val & 1 != 0,
+            MTIMECMP_LO => self.mtimecmp = (self.mtimecmp & !0xffffffff) | val as u64,
+            MTIMECMP_HI => self.mtimecmp = (self.mtimecmp & 0xffffffff) | ((val as u64) << 32),
+            MTIME_LO => *mtime = (*mtime & !0xffffffff) | val as u64,
+            MTIME_HI => *mtime = (*mtime & 0xffffffff) | ((val as u64) << 32),
+            _ => {} // Reserved, writes are ignored.
+        }
+        true
+    }
+
+    pub fn get_mtimecmp(&self) -> u64 {
+        self.mtimecmp
+    }
+
+    /// Machine software interrupt (MIP.MSIP) level.
+    pub fn software_pending(&self) -> bool {
+        self.msip
+    }
+
+    /// Machine timer interrupt (MIP.MTIP) level at time `mtime`.
+    pub fn timer_pending(&self, mtime: u64) -> bool {
+        mtime >= self.mtimecmp
+    }
+}
@@ -0,0 +1,105 @@
+// Host-defined instructions in the custom-0..3 major opcodes. The host
+// registers a handler per opcode with `MiniRV32IMAState::register_custom_opcode`;
+// opcodes without one stay illegal instructions.
+
+use crate::rv32ima::{MiniRV32IMAState, Privilege, TrapCause};
+
+/// The four major opcodes reserved for custom extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CustomOpcode {
+    Custom0 = 0,
+    Custom1 = 1,
+    Custom2 = 2,
+    Custom3 = 3,
+}
+
+impl CustomOpcode {
+    /// The custom opcode in bits 6:0 of `ir`, if any.
+    pub fn from_instruction(ir: u32) -> Option<Self> {
+        match ir & 0x7f {
+            0x0b => Some(CustomOpcode::Custom0),
+            0x2b => Some(CustomOpcode::Custom1),
+            0x5b => Some(CustomOpcode::Custom2),
+            0x7b => Some(CustomOpcode::Custom3),
+            _ => None,
+        }
+    }
+}
+
+/// A custom instruction decoded with the R-type field layout. Handlers using
+/// other layouts can take what they need from `ir`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CustomInstruction {
+    pub opcode: CustomOpcode,
+    /// The instruction bits, expanded if it was compressed.
+    pub ir: u32,
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub funct3: u32,
+    pub funct7: u32,
+    /// Values of x[rs1] and x[rs2] when the instruction started.
+    pub rs1_val: u32,
+    pub rs2_val: u32,
+}
+
+/// The hart state a handler may change.
+pub struct CustomContext<'a> {
+    pub(crate) hart: &'a mut MiniRV32IMAState,
+    pub(crate) image: &'a mut [u8],
+}
+
+impl CustomContext<'_> {
+    /// x[`regnum`].
+    pub fn reg(&self, regnum: u32) -> u32 {
+        self.hart.get_reg(regnum as usize)
+    }
+
+    /// Writes x[`regnum`]. Writes to x0 are ignored, as are writes to
+    /// x16..x31 on an RV32E hart, which doesn't have them.
+    pub fn set_reg(&mut self, regnum: u32, val: u32) {
+        if self.hart.get_config().isa.e && regnum >= 16 {
+            return;
+        }
+        self.hart.set_reg(regnum as usize, val);
+    }
+
+    /// The privilege the instruction runs in.
+    pub fn privilege(&self) -> Privilege {
+        self.hart.get_privilege()
+    }
+
+    /// Loads `size` (1, 2 or 4) bytes at virtual address `vaddr`, zero-extended,
+    /// exactly like a guest load: through the MMU and PMP, with the same
+    /// misaligned policy and devices. On failure returns the exception and
+    /// mtval, which the handler can raise with `CustomOutcome::Trap`.
+    pub fn load(&mut self, vaddr: u32, size: u32) -> Result<u32, (TrapCause, u32)> {
+        self.hart.load(self.image, vaddr, size)
+    }
+
+    /// Stores the low `size` (1, 2 or 4) bytes of `val` at virtual address
+    /// `vaddr` like a guest store, see `load`.
+    pub fn store(&mut self, vaddr: u32, size: u32, val: u32) -> Result<(), (TrapCause, u32)> {
+        self.hart.store(self.image, vaddr, size, val)
+    }
+}
+
+/// How a custom instruction finished.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CustomOutcome {
+    /// The instruction retires, writing `value` to rd if there is one. It
+    /// takes `extra_cycles` cycles on top of the usual one.
+    Retire {
+        value: Option<u32>,
+        extra_cycles: u64,
+    },
+    /// The instruction raises `cause` with `mtval`, like a built-in one.
+    Trap { cause: TrapCause, mtval: u32 },
+}
+
+clone_box_fn!(
+    /// Host handler executing the instructions of one custom opcode.
+    CustomHandler: FnMut(&CustomInstruction, &mut CustomContext) -> CustomOutcome
+);
+
+pub type CustomInsnFn = Box<dyn CustomHandler>;
@@ -0,0 +1,211 @@
+// Flattened device tree (DTB) writer, enough to describe the emulated
+// machine to a Linux kernel. Nodes and properties are emitted in order, the
+// strings block is deduplicated and there are no memory reservations.
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+// A single empty entry terminates the memory reservation block.
+const FDT_RSVMAP_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+#[derive(Default)]
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push_u32(&mut self, val: u32) {
+        self.structure.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    // Offset of `name` in the strings block, adding it if needed.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&b| b == 0) {
+            if s == name.as_bytes() && offset < self.strings.len() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+
+    /// Opens a node. The root node is named "".
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "end_node without begin_node");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        assert!(self.depth > 0, "properties must be inside a node");
+        let nameoff = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(nameoff);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// An empty property, such as `interrupt-controller`.
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, val: u32) {
+        self.property(name, &val.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, val: &str) {
+        self.property_strings(name, &[val]);
+    }
+
+    /// A string list, such as `compatible`.
+    pub fn property_strings(&mut self, name: &str, vals: &[&str]) {
+        let mut value = Vec::new();
+        for val in vals {
+            value.extend_from_slice(val.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    /// Returns the finished blob. Every node must have been closed.
+    pub fn finish(mut self, boot_cpuid: u32) -> Vec<u8> {
+        assert!(self.depth == 0, "unclosed device tree node");
+        self.push_u32(FDT_END);
+
+        let off_rsvmap = FDT_HEADER_SIZE;
+        let off_struct = off_rsvmap + FDT_RSVMAP_SIZE;
+        let off_strings = off_struct + self.structure.len();
+        let total = off_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total);
+        for field in [
+            FDT_MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.resize(off_struct, 0);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn blob_layout() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_string("compatible", "ab");
+        fdt.begin_node("cpu@0");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_null("compatible");
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish(3);
+
+        let (off_struct, off_strings) = (56, 56 + 92);
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!(be32(&blob, 8), off_struct);
+        assert_eq!(be32(&blob, 12), off_strings);
+        assert_eq!(be32(&blob, 16), 40);
+        assert_eq!(be32(&blob, 20), 17);
+        assert_eq!(be32(&blob, 24), 16);
+        assert_eq!(be32(&blob, 28), 3);
+        // Each property name is stored once.
+        assert_eq!(
+            &blob[off_strings as usize..],
+            b"#address-cells\0compatible\0"
+        );
+        assert_eq!(be32(&blob, 32), 26);
+        assert_eq!(be32(&blob, 36), off_strings - off_struct);
+        // The reservation block only holds its terminator.
+        assert!(blob[40..56].iter().all(|&b| b == 0));
+
+        // Tokens are 4-byte aligned, names and values padded with zeros.
+        let structure = [
+            FDT_BEGIN_NODE,
+            0,
+            FDT_PROP,
+            4,
+            0,
+            1,
+            FDT_PROP,
+            3,
+            15,
+            u32::from_be_bytes(*b"ab\0\0"),
+            FDT_BEGIN_NODE,
+            u32::from_be_bytes(*b"cpu@"),
+            u32::from_be_bytes(*b"0\0\0\0"),
+            FDT_PROP,
+            4,
+            0,
+            2,
+            FDT_PROP,
+            0,
+            15,
+            FDT_END_NODE,
+            FDT_END_NODE,
+            FDT_END,
+        ];
+        let words: Vec<u32> = (off_struct..off_strings)
+            .step_by(4)
+            .map(|offset| be32(&blob, offset as usize))
+            .collect();
+        assert_eq!(words, structure);
+    }
+}
@@ -0,0 +1,38 @@
+// Declares `$name` as a closure trait that can be cloned through a box, so
+// the host callbacks a hart owns are cloned along with it.
+macro_rules! clone_box_fn {
+    ($(#[$attr:meta])* $name:ident: $($bound:tt)*) => {
+        $(#[$attr])*
+        pub trait $name: $($bound)* {
+            fn clone_box(&self) -> Box<dyn $name>;
+        }
+
+        impl<F: $($bound)* + Clone + 'static> $name for F {
+            fn clone_box(&self) -> Box<dyn $name> {
+                Box::new(self.clone())
+            }
+        }
+
+        impl Clone for Box<dyn $name> {
+            fn clone(&self) -> Self {
+                // The box itself is a cloneable closure, clone what it holds.
+                (**self).clone_box()
+            }
+        }
+    };
+}
+
+mod bitmanip;
+pub mod clic;
+pub mod clint;
+pub mod custom;
+pub mod fdt;
+pub mod linux;
+pub mod mmio;
+pub mod mmu;
+pub mod plic;
+pub mod pmp;
+mod rv32c;
+pub mod rv32ima;
+pub mod softfloat;
+pub mod uart;
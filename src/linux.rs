@@ -0,0 +1,202 @@
+// Direct boot of a Linux kernel `Image`, the way mini-rv32ima runs its nommu
+// kernel: there is no firmware, the kernel starts in M-mode at the start of
+// RAM with a0 = hart id and a1 = address of a generated device tree.
+//
+// RAM layout: kernel at the bottom, device tree at the top and the initrd,
+// page aligned, right below the device tree.
+
+use crate::clint::CLINT_SIZE;
+use crate::fdt::FdtWriter;
+use crate::plic::{PLIC_SIZE, PLIC_SOURCES};
+use crate::rv32ima::{MachineConfig, MiniRV32IMAState};
+use crate::uart::{UART_BASE, UART_SIZE};
+
+// phandle of the hart's local interrupt controller.
+const CPU_INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+// Clock the UART reports, Linux only uses it to compute divisors.
+const UART_CLOCK: u32 = 1000000;
+
+#[derive(Clone, Debug)]
+pub struct LinuxBootConfig {
+    /// Kernel command line (/chosen/bootargs).
+    pub cmdline: String,
+    /// Rate of the `time` CSR and CLINT mtime, in Hz.
+    pub timebase_frequency: u32,
+    /// Where the host registered its `Uart8250`, used as the console.
+    pub uart_base: u32,
+}
+
+impl Default for LinuxBootConfig {
+    fn default() -> Self {
+        Self {
+            cmdline: "earlycon console=ttyS0".to_string(),
+            timebase_frequency: 1000000,
+            uart_base: UART_BASE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootError {
+    /// The kernel, initrd and device tree don't fit in RAM together.
+    TooLarge,
+}
+
+/// Loads `kernel`, `initrd` and a device tree describing `cpu` into `image`
+/// (the RAM later passed to `step`) and points the hart at the kernel entry.
+/// Returns the physical address of the device tree.
+pub fn boot_linux(
+    cpu: &mut MiniRV32IMAState,
+    image: &mut [u8],
+    kernel: &[u8],
+    initrd: Option<&[u8]>,
+    config: &LinuxBootConfig,
+) -> Result<u32, BootError> {
+    let ram_base = cpu.get_config().ram_base;
+    let ram_size = (cpu.get_config().ram_size as usize).min(image.len());
+    let initrd = initrd.unwrap_or(&[]);
+
+    // The blob's size doesn't depend on the initrd addresses it holds.
+    let dtb_len = device_tree(cpu, config, Some((0, 0))).len();
+    let dtb_ofs = ram_size.checked_sub(dtb_len).ok_or(BootError::TooLarge)? & !7;
+    let initrd_ofs = dtb_ofs
+        .checked_sub(initrd.len())
+        .ok_or(BootError::TooLarge)?
+        & !0xfff;
+    if kernel.len() > initrd_ofs {
+        return Err(BootError::TooLarge);
+    }
+
+    let initrd_range = if initrd.is_empty() {
+        None
+    } else {
+        let start = ram_base + initrd_ofs as u32;
+        Some((start, start + initrd.len() as u32))
+    };
+    let dtb = device_tree(cpu, config, initrd_range);
+    image[..kernel.len()].copy_from_slice(kernel);
+    image[initrd_ofs..initrd_ofs + initrd.len()].copy_from_slice(initrd);
+    image[dtb_ofs..dtb_ofs + dtb.len()].copy_from_slice(&dtb);
+
+    let dtb_addr = ram_base + dtb_ofs as u32;
+    cpu.set_pc(ram_base);
+    cpu.set_reg(10, 0); // a0: hart id
+    cpu.set_reg(11, dtb_addr); // a1: device tree
+    Ok(dtb_addr)
+}
+
+// The riscv,isa string for the extensions enabled in misa, followed by the
+// enabled multi-letter extensions.
+fn isa_string(misa: u32, machine: &MachineConfig) -> String {
+    let mut isa = "rv32".to_string();
+    for ext in "iemafdc".chars() {
+        if misa & (1 << (ext as u32 - 'a' as u32)) != 0 {
+            isa.push(ext);
+        }
+    }
+    let isa_config = &machine.isa;
+    for (name, enabled) in [
+        ("zicntr", isa_config.zicntr),
+        ("zicsr", isa_config.zicsr),
+        ("zba", isa_config.zba),
+        ("zbb", isa_config.zbb),
+        ("zbs", isa_config.zbs),
+    ] {
+        if enabled {
+            isa.push('_');
+            isa.push_str(name);
+        }
+    }
+    isa
+}
+
+fn device_tree(
+    cpu: &MiniRV32IMAState,
+    config: &LinuxBootConfig,
+    initrd: Option<(u32, u32)>,
+) -> Vec<u8> {
+    let machine = cpu.get_config();
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 1);
+    fdt.property_string("compatible", "ruvm32");
+    fdt.property_string("model", "ruvm32");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", &config.cmdline);
+    fdt.property_string("stdout-path", &format!("/soc/uart@{:x}", config.uart_base));
+    if let Some((start, end)) = initrd {
+        fdt.property_u32("linux,initrd-start", start);
+        fdt.property_u32("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", machine.ram_base));
+    fdt.property_string("device_type", "memory");
+    fdt.property_cells("reg", &[machine.ram_base, machine.ram_size]);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", config.timebase_frequency);
+    fdt.begin_node("cpu@0");
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", 0);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    fdt.property_string("riscv,isa", &isa_string(cpu.get_misa(), machine));
+    // The kernel runs in M-mode without translation.
+    fdt.property_string("mmu-type", "riscv,none");
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_null("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 1);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+
+    fdt.begin_node(&format!("uart@{:x}", config.uart_base));
+    fdt.property_string("compatible", "ns16550a");
+    fdt.property_cells("reg", &[config.uart_base, UART_SIZE]);
+    fdt.property_u32("clock-frequency", UART_CLOCK);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("clint@{:x}", machine.clint_base));
+    fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    fdt.property_cells("reg", &[machine.clint_base, CLINT_SIZE]);
+    // Machine software (3) and timer (7) interrupts of hart 0.
+    fdt.property_cells(
+        "interrupts-extended",
+        &[CPU_INTC_PHANDLE, 3, CPU_INTC_PHANDLE, 7],
+    );
+    fdt.end_node();
+
+    fdt.begin_node(&format!("interrupt-controller@{:x}", machine.plic_base));
+    fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    fdt.property_cells("reg", &[machine.plic_base, PLIC_SIZE]);
+    fdt.property_u32("#address-cells", 0);
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_null("interrupt-controller");
+    // Context 0 is M-mode external (11), context 1 S-mode external (9).
+    fdt.property_cells(
+        "interrupts-extended",
+        &[CPU_INTC_PHANDLE, 11, CPU_INTC_PHANDLE, 9],
+    );
+    fdt.property_u32("riscv,ndev", PLIC_SOURCES - 1);
+    fdt.property_u32("phandle", PLIC_PHANDLE);
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish(0)
+}
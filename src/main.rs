@@ -1,6 +1,36 @@
 use std::env;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
-mod rv32ima;
+use ruvm32::custom::{CustomContext, CustomInstruction, CustomOpcode, CustomOutcome};
+use ruvm32::linux::{LinuxBootConfig, boot_linux};
+use ruvm32::rv32ima;
+use ruvm32::rv32ima::{MachineConfig, MiniRV32IMAState, StepResult, TrapCause};
+use ruvm32::uart::{UART_BASE, UART_SIZE, Uart8250};
+
+// RAM given to a Linux guest.
+const LINUX_RAM_SIZE: u32 = 64 << 20;
+
+// Host calls of the FreeRTOS demo, a custom-0 instruction whose funct3 is
+// one of configUVM32_CALL_* in its FreeRTOSConfig.h. Ecalls are left to the
+// guest's trap handler, the port yields with them.
+const CALL_TRACE_TICK: u32 = 0;
+
+fn demo_call(insn: &CustomInstruction, _: &mut CustomContext) -> CustomOutcome {
+    match insn.funct3 {
+        CALL_TRACE_TICK => {
+            println!("TICK{}", insn.rs1_val);
+            CustomOutcome::Retire {
+                value: None,
+                extra_cycles: 0,
+            }
+        }
+        _ => CustomOutcome::Trap {
+            cause: TrapCause::IllegalInstruction,
+            mtval: insn.ir,
+        },
+    }
+}
 
 fn dump_state(rv32_iresisters: &rv32ima::RV32IRegisters) {
     println!("PC: {:08x}", rv32_iresisters.pc);
@@ -18,96 +48,149 @@ fn dump_state(rv32_iresisters: &rv32ima::RV32IRegisters) {
     println!("extraflags:{:08x}", rv32_iresisters.extraflags);
 }
 
+// How time passes while the guest waits in WFI.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum IdleMode {
+    // mtime follows the host clock in microseconds, idle time is slept.
+    RealTime,
+    // mtime only advances with the guest, idle time is skipped.
+    Simulation,
+}
+
+// Microseconds since the first call, the time source of real-time mode.
+fn host_micros() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+fn configure_time(cpu: &mut MiniRV32IMAState, mode: IdleMode) {
+    if mode == IdleMode::RealTime {
+        cpu.set_time_source(Some(host_micros));
+    }
+}
+
+// Lets time pass until the next timer deadline while the guest waits in WFI.
+// Returns false if the timer can't wake it, being unarmed or already past, as
+// nothing else would.
+fn idle(cpu: &mut MiniRV32IMAState, mode: IdleMode) -> bool {
+    let now = cpu.get_time();
+    let Some(deadline) = cpu.get_next_deadline().filter(|&deadline| deadline > now) else {
+        return false;
+    };
+    match mode {
+        IdleMode::RealTime => std::thread::sleep(Duration::from_micros(deadline - now)),
+        IdleMode::Simulation => cpu.set_time(deadline),
+    }
+    true
+}
+
 fn callback_on_trap(trap: u32) {
     println!("Trap occurred with code {:08x}", trap);
 }
 
+// Boots `ruvm32 --linux <Image> [initrd]` with the console on stdout and runs
+// until the guest halts or hits a fatal fault.
+fn run_linux(kernel_path: &str, initrd_path: Option<&String>, mode: IdleMode) {
+    let kernel = std::fs::read(kernel_path).expect("Failed to read kernel image");
+    let initrd = initrd_path.map(|path| std::fs::read(path).expect("Failed to read initrd"));
+
+    let config = MachineConfig::with_ram(rv32ima::MINIRV32_RAM_IMAGE_OFFSET, LINUX_RAM_SIZE);
+    let mut cpu = MiniRV32IMAState::with_config(config, None);
+    configure_time(&mut cpu, mode);
+    cpu.register_mmio(UART_BASE, UART_SIZE, Box::new(Uart8250::stdout()));
+    let mut memory: Vec<u8> = vec![0; config.ram_size as usize];
+    boot_linux(
+        &mut cpu,
+        &mut memory,
+        &kernel,
+        initrd.as_deref(),
+        &LinuxBootConfig::default(),
+    )
+    .expect("Kernel and initrd don't fit in RAM");
+
+    loop {
+        match cpu.step(&mut memory, 0, 1024) {
+            // The kernel handles its own system calls and breakpoints.
+            StepResult::Ecall { privilege } => {
+                cpu.raise_exception(TrapCause::ecall_from(privilege), 0)
+            }
+            StepResult::Breakpoint => cpu.raise_exception(TrapCause::Breakpoint, cpu.get_pc()),
+            StepResult::Halt => break,
+            StepResult::Wfi => {
+                if !idle(&mut cpu, mode) {
+                    println!("Guest waits for an interrupt that can't come");
+                    break;
+                }
+            }
+            StepResult::Retired(_) | StepResult::Fault { .. } => {}
+        }
+    }
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let path: String;
-    if args.len() < 2 {
-        path = "/home/yango/proj/ruvm32/freertos/FreeRTOS-LTS/FreeRTOS/FreeRTOS-Kernel/test1.bin"
+    // --realtime runs guest time at wall-clock speed instead of skipping
+    // through idle periods.
+    let mut args: Vec<String> = env::args().collect();
+    let mode = match args.iter().position(|arg| arg == "--realtime") {
+        Some(index) => {
+            args.remove(index);
+            IdleMode::RealTime
+        }
+        None => IdleMode::Simulation,
+    };
+    if args.len() >= 3 && args[1] == "--linux" {
+        run_linux(&args[2], args.get(3), mode);
+        return;
+    }
+    let path: String = if args.len() < 2 {
+        "/home/yango/proj/ruvm32/freertos/FreeRTOS-LTS/FreeRTOS/FreeRTOS-Kernel/test1.bin"
             .to_string()
-        //path = "/home/yango/proj/ruvm32/example_in_c/test1.bin".to_string()
+        //"/home/yango/proj/ruvm32/example_in_c/test1.bin".to_string()
     } else {
-        path = args[1].clone();
-    }
+        args[1].clone()
+    };
 
     let rom = std::fs::read(path).expect("Failed to read ROM file");
 
     let mut cpu = rv32ima::MiniRV32IMAState::new(Some(callback_on_trap));
+    configure_time(&mut cpu, mode);
+    cpu.register_custom_opcode(CustomOpcode::Custom0, Box::new(demo_call));
 
-    let mut memory: Vec<u8> = vec![0; rv32ima::UVM32_MEMORY_SIZE as usize];
+    let mut memory: Vec<u8> = vec![0; cpu.get_config().ram_size as usize];
     memory[0..rom.len()].copy_from_slice(&rom);
 
     loop {
         let ret = cpu.step(&mut memory, 0, 1);
         match ret {
-            0 => {
+            StepResult::Retired(_) => {
                 println!("Stepped successfully to PC={:08x}", cpu.get_pc());
                 if cpu.get_pc() == 0x80000138 {
                     println!("PING!!!!");
                 }
             }
-            12 => {
-                /*
-                // Fetch registers used by syscall
-                const uint32_t syscall = vmst->_core.regs[17];  // a7
-                // on exception we should jump to mtvec, but we handle directly
-                // and skip over the ecall instruction
-                vmst->_core.pc += 4; */
-
-                let syscall = cpu.get_reg(17); // a7 
-                match syscall {
-                    rv32ima::UVM32_SYSCALL_HALT => {
-                        println!("SYSCALL HALT encountered at PC={:08x}", cpu.get_pc());
-                        break;
-                    }
-                    64 => {
-                        println!("TICK1 {:08x} at PC={:08x}", syscall, cpu.get_pc());
-                        let mvtec = cpu.get_mvtec();
-                        println!(" mtvec = {:08x}", mvtec);
-
-                        cpu.increment_pc(4);
-                        //break;
-                    }
-                    65 => {
-                        println!("TICK2 {:08x} at PC={:08x}", syscall, cpu.get_pc());
-                        cpu.increment_pc(4);
-                        //break;
-                    }
-                    66 => {
-                        println!(
-                            "External interrupt handler {:08x} at PC={:08x}",
-                            syscall,
-                            cpu.get_pc()
-                        );
-                        cpu.increment_pc(4);
-                        //break;
-                    }
-                    67 => {
-                        println!(
-                            "Timer setup called {:08x} at PC={:08x}",
-                            syscall,
-                            cpu.get_pc()
-                        );
-                        cpu.increment_pc(4);
-                        //break;
-                    }
-                    _ => {
-                        println!("Unknown SYSCALL {:08x} at PC={:08x}", syscall, cpu.get_pc());
-                        dump_state(&cpu.get_state());
-                        let mvtec = cpu.get_mvtec();
-                        cpu.set_mcause(11); // ecall from M-mode
-                        println!("Jumping to mtvec = {:08x}", mvtec);
-                        cpu.set_pc(mvtec);
-
-                        //break;
-                    }
+            StepResult::Ecall { privilege } => {
+                // FreeRTOS yields with an ecall, its trap handler takes it.
+                println!(
+                    "ECALL at PC={:08x}, jumping to mtvec = {:08x}",
+                    cpu.get_pc(),
+                    cpu.get_mvtec()
+                );
+                cpu.raise_exception(TrapCause::ecall_from(privilege), 0);
+            }
+            StepResult::Halt => {
+                println!("SYSCALL HALT encountered at PC={:08x}", cpu.get_pc());
+                break;
+            }
+            StepResult::Wfi => {
+                if !idle(&mut cpu, mode) {
+                    println!("WFI with no wakeup source at PC={:08x}", cpu.get_pc());
+                    dump_state(&cpu.get_state());
+                    break;
                 }
             }
-            _ => {
-                println!("Halting with code {}", ret);
+            StepResult::Breakpoint | StepResult::Fault { .. } => {
+                println!("Halting with {:?}", ret);
+                dump_state(&cpu.get_state());
                 break;
             }
         }
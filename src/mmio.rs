@@ -0,0 +1,139 @@
+// Memory-mapped I/O bus. The host registers devices at address ranges outside
+// RAM and `MiniRV32IMAState::step` dispatches guest loads and stores to them.
+
+/// A memory-mapped peripheral. Offsets are relative to the base address the
+/// device was registered at and the device keeps whatever state it needs.
+///
+/// Every access width has its own callback. The defaults reject the access,
+/// which the guest sees as a load/store access fault, so a device only
+/// implements the widths it supports.
+///
+/// Devices are cloned with the hart that owns them, so they must be `Clone`.
+pub trait MmioDevice: MmioDeviceClone {
+    fn read8(&mut self, _offset: u32) -> Option<u8> {
+        None
+    }
+
+    fn read16(&mut self, _offset: u32) -> Option<u16> {
+        None
+    }
+
+    fn read32(&mut self, _offset: u32) -> Option<u32> {
+        None
+    }
+
+    fn write8(&mut self, _offset: u32, _val: u8) -> bool {
+        false
+    }
+
+    fn write16(&mut self, _offset: u32, _val: u16) -> bool {
+        false
+    }
+
+    fn write32(&mut self, _offset: u32, _val: u32) -> bool {
+        false
+    }
+}
+
+pub trait MmioDeviceClone {
+    fn clone_box(&self) -> Box<dyn MmioDevice>;
+}
+
+impl<T: MmioDevice + Clone + 'static> MmioDeviceClone for T {
+    fn clone_box(&self) -> Box<dyn MmioDevice> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn MmioDevice> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+#[derive(Clone)]
+struct MmioMapping {
+    base: u32,
+    size: u32,
+    device: Box<dyn MmioDevice>,
+}
+
+impl MmioMapping {
+    // Offset of the access if it lies entirely inside this mapping.
+    fn offset_of(&self, addr: u32, size: u32) -> Option<u32> {
+        let offset = addr.wrapping_sub(self.base);
+        if offset < self.size && self.size - offset >= size {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MmioBus {
+    mappings: Vec<MmioMapping>,
+}
+
+impl MmioBus {
+    /// Maps `device` at `base..base + size` and returns its index.
+    ///
+    /// Panics if the range is empty, wraps around the address space or
+    /// overlaps a device that is already registered.
+    pub fn register(&mut self, base: u32, size: u32, device: Box<dyn MmioDevice>) -> usize {
+        assert!(
+            size != 0 && base.checked_add(size - 1).is_some(),
+            "invalid MMIO range {:#010x}+{:#x}",
+            base,
+            size
+        );
+        if let Some(other) = self
+            .mappings
+            .iter()
+            .find(|m| base < m.base.wrapping_add(m.size) && m.base < base.wrapping_add(size))
+        {
+            panic!(
+                "MMIO range {:#010x}+{:#x} overlaps {:#010x}+{:#x}",
+                base, size, other.base, other.size
+            );
+        }
+        self.mappings.push(MmioMapping { base, size, device });
+        self.mappings.len() - 1
+    }
+
+    pub fn device_mut(&mut self, index: usize) -> Option<&mut (dyn MmioDevice + 'static)> {
+        self.mappings.get_mut(index).map(|m| m.device.as_mut())
+    }
+
+    fn find(&mut self, addr: u32, size: u32) -> Option<(&mut MmioMapping, u32)> {
+        self.mappings
+            .iter_mut()
+            .find_map(|m| m.offset_of(addr, size).map(|offset| (m, offset)))
+    }
+
+    /// Reads `size` (1, 2 or 4) bytes, zero-extended. `None` means no device
+    /// accepted the access.
+    pub fn load(&mut self, addr: u32, size: u32) -> Option<u32> {
+        let (mapping, offset) = self.find(addr, size)?;
+        match size {
+            1 => mapping.device.read8(offset).map(|v| v as u32),
+            2 => mapping.device.read16(offset).map(|v| v as u32),
+            4 => mapping.device.read32(offset),
+            _ => None,
+        }
+    }
+
+    /// Writes the low `size` (1, 2 or 4) bytes of `val`. `false` means no
+    /// device accepted the access.
+    pub fn store(&mut self, addr: u32, size: u32, val: u32) -> bool {
+        let Some((mapping, offset)) = self.find(addr, size) else {
+            return false;
+        };
+        match size {
+            1 => mapping.device.write8(offset, val as u8),
+            2 => mapping.device.write16(offset, val as u16),
+            4 => mapping.device.write32(offset, val),
+            _ => false,
+        }
+    }
+}
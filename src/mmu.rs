@@ -0,0 +1,95 @@
+// Sv32 page-based virtual memory: satp/PTE layouts, the leaf permission check
+// and a small direct-mapped TLB. The page walk itself lives in
+// `MiniRV32IMAState`, which owns the physical memory it reads PTEs from.
+
+use crate::rv32ima::{AccessType, Privilege};
+
+pub const SATP_MODE_SV32: u32 = 1 << 31;
+/// Root page table PPN. ASIDs are not implemented, so satp.ASID reads as zero.
+pub const SATP_PPN: u32 = 0x003fffff;
+
+pub const PTE_V: u32 = 1 << 0;
+pub const PTE_R: u32 = 1 << 1;
+pub const PTE_W: u32 = 1 << 2;
+pub const PTE_X: u32 = 1 << 3;
+pub const PTE_U: u32 = 1 << 4;
+pub const PTE_G: u32 = 1 << 5;
+pub const PTE_A: u32 = 1 << 6;
+pub const PTE_D: u32 = 1 << 7;
+
+pub const PAGE_SIZE: u32 = 4096;
+
+const TLB_ENTRIES: usize = 64;
+
+/// Whether a leaf PTE with `flags` lets `privilege` make `access`. `sum` and
+/// `mxr` are the mstatus bits of the same names.
+pub fn leaf_allows(
+    flags: u32,
+    access: AccessType,
+    privilege: Privilege,
+    sum: bool,
+    mxr: bool,
+) -> bool {
+    if flags & PTE_U != 0 {
+        // S-mode never executes user pages and only touches their data with SUM.
+        if privilege == Privilege::Supervisor && (access == AccessType::Fetch || !sum) {
+            return false;
+        }
+    } else if privilege == Privilege::User {
+        return false;
+    }
+    match access {
+        AccessType::Fetch => flags & PTE_X != 0,
+        AccessType::Load => flags & PTE_R != 0 || (mxr && flags & PTE_X != 0),
+        AccessType::Store => flags & PTE_W != 0,
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct TlbEntry {
+    valid: bool,
+    vpn: u32,
+    ppn: u32,
+    flags: u32,
+}
+
+/// Caches leaf translations per 4 KiB virtual page, superpages included.
+/// Entries keep the PTE flags so permissions are checked on every hit.
+#[derive(Clone, Copy)]
+pub struct Tlb {
+    entries: [TlbEntry; TLB_ENTRIES],
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self {
+            entries: [TlbEntry::default(); TLB_ENTRIES],
+        }
+    }
+}
+
+impl Tlb {
+    /// The physical page number and PTE flags cached for virtual page `vpn`.
+    pub fn lookup(&self, vpn: u32) -> Option<(u32, u32)> {
+        let entry = &self.entries[vpn as usize % TLB_ENTRIES];
+        if entry.valid && entry.vpn == vpn {
+            Some((entry.ppn, entry.flags))
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&mut self, vpn: u32, ppn: u32, flags: u32) {
+        self.entries[vpn as usize % TLB_ENTRIES] = TlbEntry {
+            valid: true,
+            vpn,
+            ppn,
+            flags,
+        };
+    }
+
+    /// Drops every cached translation (SFENCE.VMA, satp writes).
+    pub fn flush(&mut self) {
+        self.entries = [TlbEntry::default(); TLB_ENTRIES];
+    }
+}
@@ -0,0 +1,310 @@
+// PLIC (Platform-Level Interrupt Controller) with the SiFive register layout,
+// as described by the "riscv,plic0" device tree binding. There is a single
+// hart with two contexts: 0 drives MIP.MEIP and 1 drives MIP.SEIP.
+//
+// Interrupt lines are level-triggered: a source is pending while the host
+// holds its line high, unless it has been claimed and not completed yet.
+
+pub const PLIC_BASE: u32 = 0x0c000000;
+pub const PLIC_SIZE: u32 = 0x400000;
+
+/// Number of interrupt sources, including the reserved source 0.
+pub const PLIC_SOURCES: u32 = 32;
+/// Number of contexts: hart 0 M-mode and S-mode.
+pub const PLIC_CONTEXTS: usize = 2;
+pub const PLIC_CONTEXT_M: usize = 0;
+pub const PLIC_CONTEXT_S: usize = 1;
+
+const PRIORITY: u32 = 0x000000;
+const PENDING: u32 = 0x001000;
+const ENABLE: u32 = 0x002000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT: u32 = 0x200000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+const THRESHOLD: u32 = 0x0;
+const CLAIM: u32 = 0x4;
+
+// Priorities and thresholds are 3 bits wide.
+const PRIORITY_MASK: u32 = 7;
+
+#[derive(Clone, Copy)]
+pub struct Plic {
+    base: u32,
+    priority: [u32; PLIC_SOURCES as usize],
+    /// Line levels set by the host, one bit per source.
+    levels: u32,
+    /// Sources claimed and not completed yet.
+    claimed: u32,
+    enable: [u32; PLIC_CONTEXTS],
+    threshold: [u32; PLIC_CONTEXTS],
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new(PLIC_BASE)
+    }
+}
+
+impl Plic {
+    pub fn new(base: u32) -> Self {
+        Self {
+            base,
+            priority: [0; PLIC_SOURCES as usize],
+            levels: 0,
+            claimed: 0,
+            enable: [0; PLIC_CONTEXTS],
+            threshold: [0; PLIC_CONTEXTS],
+        }
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < PLIC_SIZE
+    }
+
+    /// Drives interrupt line `irq` (1..PLIC_SOURCES) high or low.
+    pub fn set_level(&mut self, irq: u32, level: bool) {
+        assert!(
+            (1..PLIC_SOURCES).contains(&irq),
+            "PLIC interrupt {} out of range",
+            irq
+        );
+        if level {
+            self.levels |= 1 << irq;
+        } else {
+            self.levels &= !(1 << irq);
+        }
+    }
+
+    /// Pending sources, one bit per source.
+    pub fn get_pending(&self) -> u32 {
+        self.levels & !self.claimed
+    }
+
+    /// Whether `context` has an enabled source pending above its threshold,
+    /// which is the level of its MEIP or SEIP bit.
+    pub fn interrupt_pending(&self, context: usize) -> bool {
+        self.best(context)
+            .is_some_and(|irq| self.priority[irq as usize] > self.threshold[context])
+    }
+
+    // The enabled pending source of `context` with the highest priority, the
+    // lowest numbered one on ties. Sources with priority 0 never interrupt.
+    fn best(&self, context: usize) -> Option<u32> {
+        let candidates = self.get_pending() & self.enable[context];
+        (1..PLIC_SOURCES)
+            .filter(|irq| candidates & (1 << irq) != 0 && self.priority[*irq as usize] > 0)
+            .min_by_key(|irq| (std::cmp::Reverse(self.priority[*irq as usize]), *irq))
+    }
+
+    // Splits a context register offset into the context and the register.
+    fn context_register(offset: u32) -> Option<(usize, u32)> {
+        let context = (offset.checked_sub(CONTEXT)? / CONTEXT_STRIDE) as usize;
+        (context < PLIC_CONTEXTS).then_some((context, offset % CONTEXT_STRIDE))
+    }
+
+    /// Reads the 32-bit register at `addr`. Reading a claim register claims
+    /// the source it returns. Returns `None` for misaligned accesses.
+    pub fn load(&mut self, addr: u32) -> Option<u32> {
+        let offset = addr.wrapping_sub(self.base);
+        if offset & 3 != 0 {
+            return None;
+        }
+        Some(match offset {
+            PRIORITY..PENDING => self
+                .priority
+                .get(((offset - PRIORITY) / 4) as usize)
+                .copied()
+                .unwrap_or(0),
+            PENDING => self.get_pending(),
+            ENABLE..CONTEXT => {
+                let word = offset - ENABLE;
+                match self.enable.get((word / ENABLE_STRIDE) as usize) {
+                    Some(&enable) if word.is_multiple_of(ENABLE_STRIDE) => enable,
+                    _ => 0,
+                }
+            }
+            _ => match Self::context_register(offset) {
+                Some((context, THRESHOLD)) => self.threshold[context],
+                Some((context, CLAIM)) => match self.best(context) {
+                    Some(irq) => {
+                        self.claimed |= 1 << irq;
+                        irq
+                    }
+                    None => 0,
+                },
+                _ => 0, // Reserved, reads as zero.
+            },
+        })
+    }
+
+    /// Writes the 32-bit register at `addr`. Writing a claim register
+    /// completes the source written, if it is enabled for that context.
+    /// Returns `false` for misaligned accesses.
+    pub fn store(&mut self, addr: u32, val: u32) -> bool {
+        let offset = addr.wrapping_sub(self.base);
+        if offset & 3 != 0 {
+            return false;
+        }
+        match offset {
+            PRIORITY..PENDING => {
+                // Source 0 doesn't exist, its priority is hardwired to zero.
+                let irq = ((offset - PRIORITY) / 4) as usize;
+                if (1..PLIC_SOURCES as usize).contains(&irq) {
+                    self.priority[irq] = val & PRIORITY_MASK;
+                }
+            }
+            ENABLE..CONTEXT => {
+                let word = offset - ENABLE;
+                if let Some(enable) = self.enable.get_mut((word / ENABLE_STRIDE) as usize)
+                    && word.is_multiple_of(ENABLE_STRIDE)
+                {
+                    *enable = val & !1;
+                }
+            }
+            _ => match Self::context_register(offset) {
+                Some((context, THRESHOLD)) => self.threshold[context] = val & PRIORITY_MASK,
+                Some((context, CLAIM))
+                    if val < PLIC_SOURCES && self.enable[context] & (1 << val) != 0 =>
+                {
+                    self.claimed &= !(1 << val);
+                }
+                _ => {} // Pending bits are read-only, the rest is reserved.
+            },
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rv32ima::{MINIRV32_RAM_IMAGE_OFFSET, MiniRV32IMAState};
+
+    fn priority(irq: u32) -> u32 {
+        PLIC_BASE + PRIORITY + irq * 4
+    }
+
+    fn enable(context: usize) -> u32 {
+        PLIC_BASE + ENABLE + context as u32 * ENABLE_STRIDE
+    }
+
+    fn threshold(context: usize) -> u32 {
+        PLIC_BASE + CONTEXT + context as u32 * CONTEXT_STRIDE + THRESHOLD
+    }
+
+    fn claim(context: usize) -> u32 {
+        PLIC_BASE + CONTEXT + context as u32 * CONTEXT_STRIDE + CLAIM
+    }
+
+    // A PLIC with `irqs` raised and enabled for M-mode at `prio`.
+    fn plic(irqs: &[(u32, u32)]) -> Plic {
+        let mut plic = Plic::default();
+        let mut mask = 0;
+        for &(irq, prio) in irqs {
+            plic.store(priority(irq), prio);
+            plic.set_level(irq, true);
+            mask |= 1 << irq;
+        }
+        plic.store(enable(PLIC_CONTEXT_M), mask);
+        plic
+    }
+
+    #[test]
+    fn priority_and_threshold_gating() {
+        let mut plic = plic(&[(3, 0)]);
+        // Priority 0 never interrupts.
+        assert!(!plic.interrupt_pending(PLIC_CONTEXT_M));
+        plic.store(priority(3), 2);
+        assert!(plic.interrupt_pending(PLIC_CONTEXT_M));
+        // Only priorities above the threshold interrupt.
+        plic.store(threshold(PLIC_CONTEXT_M), 2);
+        assert!(!plic.interrupt_pending(PLIC_CONTEXT_M));
+        plic.store(threshold(PLIC_CONTEXT_M), 1);
+        assert!(plic.interrupt_pending(PLIC_CONTEXT_M));
+        // Not enabled for S-mode.
+        assert!(!plic.interrupt_pending(PLIC_CONTEXT_S));
+        // Priorities and thresholds are 3 bits wide.
+        plic.store(priority(3), 0xf);
+        assert_eq!(plic.load(priority(3)), Some(7));
+        plic.store(threshold(PLIC_CONTEXT_M), 0xf);
+        assert_eq!(plic.load(threshold(PLIC_CONTEXT_M)), Some(7));
+    }
+
+    #[test]
+    fn claim_returns_highest_priority() {
+        let mut plic = plic(&[(2, 1), (5, 3), (4, 3)]);
+        assert_eq!(plic.load(PLIC_BASE + PENDING), Some(0b110100));
+        // Ties go to the lowest numbered source.
+        assert_eq!(plic.load(claim(PLIC_CONTEXT_M)), Some(4));
+        assert_eq!(plic.load(PLIC_BASE + PENDING), Some(0b100100));
+        assert_eq!(plic.load(claim(PLIC_CONTEXT_M)), Some(5));
+        assert_eq!(plic.load(claim(PLIC_CONTEXT_M)), Some(2));
+        assert_eq!(plic.get_pending(), 0);
+        assert!(!plic.interrupt_pending(PLIC_CONTEXT_M));
+        assert_eq!(plic.load(claim(PLIC_CONTEXT_M)), Some(0));
+    }
+
+    #[test]
+    fn complete_reenables_source() {
+        let mut plic = plic(&[(4, 1)]);
+        assert_eq!(plic.load(claim(PLIC_CONTEXT_M)), Some(4));
+        // The line is still high, but the source waits for completion.
+        assert!(!plic.interrupt_pending(PLIC_CONTEXT_M));
+        // Completing from a context the source isn't enabled in is ignored.
+        plic.store(claim(PLIC_CONTEXT_S), 4);
+        assert!(!plic.interrupt_pending(PLIC_CONTEXT_M));
+        plic.store(claim(PLIC_CONTEXT_M), 4);
+        assert!(plic.interrupt_pending(PLIC_CONTEXT_M));
+        assert_eq!(plic.get_pending(), 1 << 4);
+
+        // Completed with its line low, it stays idle.
+        assert_eq!(plic.load(claim(PLIC_CONTEXT_M)), Some(4));
+        plic.set_level(4, false);
+        plic.store(claim(PLIC_CONTEXT_M), 4);
+        assert_eq!(plic.get_pending(), 0);
+    }
+
+    // `sw rs2, imm(rs1)`.
+    fn sw(rs2: u32, imm: u32, rs1: u32) -> u32 {
+        (imm >> 5) << 25 | (rs2 << 20) | (rs1 << 15) | (2 << 12) | (imm & 0x1f) << 7 | 0x23
+    }
+
+    // `csrr rd, mip`.
+    fn csrr_mip(rd: u32) -> u32 {
+        (0x344 << 20) | (2 << 12) | (rd << 7) | 0x73
+    }
+
+    #[test]
+    fn host_lines_drive_meip_and_seip() {
+        const MEIP: u32 = 1 << 11;
+        const SEIP: u32 = 1 << 9;
+        // The guest gives source 1 priority 1 and enables it in both
+        // contexts, then reads mip once per host change.
+        let program = [
+            sw(6, 4, 5),
+            sw(7, 0, 8),
+            sw(7, ENABLE_STRIDE, 8),
+            csrr_mip(10),
+            csrr_mip(11),
+            csrr_mip(12),
+        ];
+        let mut image = vec![0u8; 0x100];
+        for (i, ir) in program.iter().enumerate() {
+            image[i * 4..i * 4 + 4].copy_from_slice(&ir.to_le_bytes());
+        }
+        let mut cpu = MiniRV32IMAState::new(None);
+        cpu.set_pc(MINIRV32_RAM_IMAGE_OFFSET);
+        cpu.set_reg(5, PLIC_BASE);
+        cpu.set_reg(6, 1);
+        cpu.set_reg(7, 1 << 1);
+        cpu.set_reg(8, enable(PLIC_CONTEXT_M));
+        cpu.step(&mut image, 0, 4);
+        cpu.raise_irq(1);
+        cpu.step(&mut image, 0, 1);
+        cpu.lower_irq(1);
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.get_reg(10) & (MEIP | SEIP), 0);
+        assert_eq!(cpu.get_reg(11) & (MEIP | SEIP), MEIP | SEIP);
+        assert_eq!(cpu.get_reg(12) & (MEIP | SEIP), 0);
+    }
+}
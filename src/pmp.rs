@@ -0,0 +1,341 @@
+// Physical Memory Protection. Each entry is a pmpcfg byte (L, A, X, W, R)
+// and a pmpaddr register holding bits 33:2 of an address. Entries are checked
+// in order and the first one that matches any byte of an access decides it.
+// The grain is 4 bytes, so every address-matching mode is available.
+
+use crate::rv32ima::{AccessType, Privilege};
+
+/// Most entries the privileged spec allows on one hart.
+pub const PMP_MAX_ENTRIES: usize = 64;
+
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 3 << 3;
+const PMP_L: u8 = 1 << 7;
+
+// Address-matching modes (pmpcfg.A).
+const PMP_OFF: u8 = 0;
+const PMP_TOR: u8 = 1;
+const PMP_NA4: u8 = 2;
+const PMP_NAPOT: u8 = 3;
+
+#[derive(Clone, Copy)]
+pub struct Pmp {
+    entries: usize,
+    cfg: [u8; PMP_MAX_ENTRIES],
+    addr: [u32; PMP_MAX_ENTRIES],
+}
+
+impl Default for Pmp {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Pmp {
+    /// A PMP unit with `entries` implemented entries, all off and unlocked.
+    /// The CSRs of unimplemented entries read as zero and ignore writes.
+    pub fn new(entries: usize) -> Self {
+        assert!(
+            entries <= PMP_MAX_ENTRIES,
+            "at most {} PMP entries",
+            PMP_MAX_ENTRIES
+        );
+        Self {
+            entries,
+            cfg: [0; PMP_MAX_ENTRIES],
+            addr: [0; PMP_MAX_ENTRIES],
+        }
+    }
+
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    fn mode(&self, i: usize) -> u8 {
+        (self.cfg[i] & PMP_A) >> 3
+    }
+
+    fn locked(&self, i: usize) -> bool {
+        self.cfg[i] & PMP_L != 0
+    }
+
+    /// Reads pmpcfg`index`, which packs the config bytes of entries
+    /// 4 * index .. 4 * index + 3.
+    pub fn read_cfg(&self, index: usize) -> u32 {
+        (0..4).fold(0, |val, byte| {
+            val | (self.cfg.get(index * 4 + byte).copied().unwrap_or(0) as u32) << (byte * 8)
+        })
+    }
+
+    /// Writes pmpcfg`index`. Locked entries keep their config, and the
+    /// reserved R = 0, W = 1 combination is stored as no access.
+    pub fn write_cfg(&mut self, index: usize, val: u32) {
+        for byte in 0..4 {
+            let i = index * 4 + byte;
+            if i >= self.entries || self.locked(i) {
+                continue;
+            }
+            let mut cfg = (val >> (byte * 8)) as u8 & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R);
+            if cfg & (PMP_R | PMP_W) == PMP_W {
+                cfg &= !PMP_W;
+            }
+            self.cfg[i] = cfg;
+        }
+    }
+
+    pub fn read_addr(&self, index: usize) -> u32 {
+        if index < self.entries {
+            self.addr[index]
+        } else {
+            0
+        }
+    }
+
+    /// Writes pmpaddr`index`, unless its entry is locked or it is the bottom
+    /// of a locked TOR range.
+    pub fn write_addr(&mut self, index: usize, val: u32) {
+        if index >= self.entries || self.locked(index) {
+            return;
+        }
+        if index + 1 < self.entries && self.locked(index + 1) && self.mode(index + 1) == PMP_TOR {
+            return;
+        }
+        self.addr[index] = val;
+    }
+
+    // Byte range [start, end) covered by entry `i`, if it is enabled.
+    fn range(&self, i: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[i] as u64;
+        match self.mode(i) {
+            PMP_OFF => None,
+            PMP_TOR => {
+                let start = if i == 0 {
+                    0
+                } else {
+                    (self.addr[i - 1] as u64) << 2
+                };
+                Some((start, addr << 2))
+            }
+            PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            PMP_NAPOT => {
+                // The trailing ones of pmpaddr encode the size.
+                let ones = self.addr[i].trailing_ones();
+                let size = 1u64 << (ones + 3);
+                let start = (addr & !((1u64 << ones) - 1)) << 2;
+                Some((start, start + size))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Whether `privilege` may make a `size`-byte `access` at `addr`.
+    ///
+    /// M-mode is only restricted by locked entries. Below M-mode an access
+    /// must fall entirely inside an entry granting it, as soon as at least
+    /// one entry is implemented.
+    pub fn allows(&self, addr: u32, size: u32, access: AccessType, privilege: Privilege) -> bool {
+        let start = addr as u64;
+        let end = start + size as u64;
+        for i in 0..self.entries {
+            let Some((lo, hi)) = self.range(i) else {
+                continue;
+            };
+            if start >= hi || end <= lo {
+                continue;
+            }
+            // A partial match fails regardless of the permissions.
+            if start < lo || end > hi {
+                return false;
+            }
+            if privilege == Privilege::Machine && !self.locked(i) {
+                return true;
+            }
+            let cfg = self.cfg[i];
+            return match access {
+                AccessType::Fetch => cfg & PMP_X != 0,
+                AccessType::Load => cfg & PMP_R != 0,
+                // W without R is reserved, so W also grants the read of an AMO.
+                AccessType::Store => cfg & PMP_W != 0,
+            };
+        }
+        privilege == Privilege::Machine || self.entries == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RWX: u8 = PMP_R | PMP_W | PMP_X;
+    const U: Privilege = Privilege::User;
+    const M: Privilege = Privilege::Machine;
+
+    // Sets entry `i` with pmpcfg byte `cfg` and pmpaddr `addr`.
+    fn set(pmp: &mut Pmp, i: usize, cfg: u8, addr: u32) {
+        pmp.write_addr(i, addr);
+        let shift = (i % 4) * 8;
+        let old = pmp.read_cfg(i / 4) & !(0xff << shift);
+        pmp.write_cfg(i / 4, old | (cfg as u32) << shift);
+    }
+
+    fn load(pmp: &Pmp, addr: u32, privilege: Privilege) -> bool {
+        pmp.allows(addr, 4, AccessType::Load, privilege)
+    }
+
+    #[test]
+    fn tor() {
+        let mut pmp = Pmp::new(4);
+        set(&mut pmp, 0, 0, 0x1000 >> 2);
+        set(&mut pmp, 1, PMP_TOR << 3 | PMP_R, 0x2000 >> 2);
+        assert!(!load(&pmp, 0xffc, U));
+        assert!(load(&pmp, 0x1000, U));
+        assert!(load(&pmp, 0x1ffc, U));
+        assert!(!load(&pmp, 0x2000, U));
+        assert!(!pmp.allows(0x1000, 4, AccessType::Store, U));
+
+        // Entry 0 in TOR mode starts at address 0.
+        let mut pmp = Pmp::new(1);
+        set(&mut pmp, 0, PMP_TOR << 3 | PMP_R, 0x100 >> 2);
+        assert!(load(&pmp, 0, U));
+        assert!(!load(&pmp, 0x100, U));
+    }
+
+    #[test]
+    fn na4() {
+        let mut pmp = Pmp::new(1);
+        set(&mut pmp, 0, PMP_NA4 << 3 | RWX, 0x1000 >> 2);
+        assert!(load(&pmp, 0x1000, U));
+        assert!(pmp.allows(0x1003, 1, AccessType::Fetch, U));
+        assert!(!load(&pmp, 0x1004, U));
+        assert!(!load(&pmp, 0xffc, U));
+    }
+
+    #[test]
+    fn napot() {
+        let mut pmp = Pmp::new(1);
+        // 4 KiB at 0x80000000: pmpaddr = base >> 2 | (size / 8 - 1).
+        set(
+            &mut pmp,
+            0,
+            PMP_NAPOT << 3 | PMP_R,
+            (0x80000000 >> 2) | 0x1ff,
+        );
+        assert!(load(&pmp, 0x80000000, U));
+        assert!(load(&pmp, 0x80000ffc, U));
+        assert!(!load(&pmp, 0x80001000, U));
+        assert!(!load(&pmp, 0x7ffffffc, U));
+
+        // All ones covers the whole 34-bit space.
+        set(&mut pmp, 0, PMP_NAPOT << 3 | PMP_R, u32::MAX);
+        assert!(load(&pmp, 0, U));
+        assert!(load(&pmp, 0xfffffffc, U));
+    }
+
+    #[test]
+    fn partial_match_fails() {
+        let mut pmp = Pmp::new(2);
+        set(&mut pmp, 0, PMP_NA4 << 3 | RWX, 0x1000 >> 2);
+        set(&mut pmp, 1, PMP_NAPOT << 3 | RWX, u32::MAX);
+        // Straddles the end of entry 0, which matches first and fails even
+        // though entry 1 would allow the whole access.
+        assert!(!load(&pmp, 0x1002, U));
+        assert!(!pmp.allows(0x1002, 4, AccessType::Load, M));
+        assert!(pmp.allows(0x1000, 4, AccessType::Load, U));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let mut pmp = Pmp::new(2);
+        set(&mut pmp, 0, PMP_NA4 << 3, 0x1000 >> 2);
+        set(&mut pmp, 1, PMP_NAPOT << 3 | RWX, u32::MAX);
+        assert!(!load(&pmp, 0x1000, U));
+        assert!(load(&pmp, 0x1004, U));
+
+        // The same entries the other way around.
+        let mut pmp = Pmp::new(2);
+        set(&mut pmp, 0, PMP_NAPOT << 3 | RWX, u32::MAX);
+        set(&mut pmp, 1, PMP_NA4 << 3, 0x1000 >> 2);
+        assert!(load(&pmp, 0x1000, U));
+    }
+
+    #[test]
+    fn no_match() {
+        // No entries implemented: everything is allowed.
+        let pmp = Pmp::new(0);
+        assert!(load(&pmp, 0x1000, U));
+
+        // With entries, only M-mode gets through when none matches.
+        let mut pmp = Pmp::new(1);
+        assert!(load(&pmp, 0x1000, M));
+        assert!(!load(&pmp, 0x1000, U));
+        set(&mut pmp, 0, PMP_NA4 << 3 | RWX, 0x2000 >> 2);
+        assert!(load(&pmp, 0x1000, M));
+        assert!(!load(&pmp, 0x1000, U));
+        assert!(!pmp.allows(0x1000, 4, AccessType::Load, Privilege::Supervisor));
+    }
+
+    #[test]
+    fn machine_mode_only_checks_locked_entries() {
+        let mut pmp = Pmp::new(2);
+        set(&mut pmp, 0, PMP_NA4 << 3, 0x1000 >> 2);
+        set(&mut pmp, 1, PMP_L | PMP_NA4 << 3 | PMP_R, 0x2000 >> 2);
+        assert!(pmp.allows(0x1000, 4, AccessType::Store, M));
+        assert!(load(&pmp, 0x2000, M));
+        assert!(!pmp.allows(0x2000, 4, AccessType::Store, M));
+        assert!(!pmp.allows(0x2000, 4, AccessType::Fetch, M));
+    }
+
+    #[test]
+    fn locked_entries_ignore_writes() {
+        let mut pmp = Pmp::new(4);
+        set(&mut pmp, 1, PMP_L | PMP_NA4 << 3 | PMP_R, 0x1000 >> 2);
+        set(&mut pmp, 1, PMP_NAPOT << 3 | RWX, 0x2000 >> 2);
+        assert_eq!(
+            pmp.read_cfg(0),
+            ((PMP_L | PMP_NA4 << 3 | PMP_R) as u32) << 8
+        );
+        assert_eq!(pmp.read_addr(1), 0x1000 >> 2);
+        // Its neighbours are still writable.
+        set(&mut pmp, 0, PMP_R, 0x3000 >> 2);
+        set(&mut pmp, 2, PMP_R, 0x4000 >> 2);
+        assert_eq!(pmp.read_addr(0), 0x3000 >> 2);
+        assert_eq!(pmp.read_addr(2), 0x4000 >> 2);
+        assert_eq!(pmp.read_cfg(0) & 0xff, PMP_R as u32);
+    }
+
+    #[test]
+    fn locked_tor_protects_previous_address() {
+        let mut pmp = Pmp::new(4);
+        set(&mut pmp, 0, 0, 0x1000 >> 2);
+        set(&mut pmp, 1, PMP_L | PMP_TOR << 3 | PMP_R, 0x2000 >> 2);
+        pmp.write_addr(0, 0);
+        assert_eq!(pmp.read_addr(0), 0x1000 >> 2);
+        assert!(!pmp.allows(0x1000, 4, AccessType::Store, M));
+        // pmpcfg0 of entry 0 itself isn't locked.
+        pmp.write_cfg(0, pmp.read_cfg(0) | PMP_R as u32);
+        assert_eq!(pmp.read_cfg(0) & 0xff, PMP_R as u32);
+
+        // A locked NA4 entry doesn't protect the previous pmpaddr.
+        let mut pmp = Pmp::new(2);
+        set(&mut pmp, 1, PMP_L | PMP_NA4 << 3 | PMP_R, 0x2000 >> 2);
+        pmp.write_addr(0, 0x1000 >> 2);
+        assert_eq!(pmp.read_addr(0), 0x1000 >> 2);
+    }
+
+    #[test]
+    fn write_only_is_reserved() {
+        let mut pmp = Pmp::new(1);
+        set(&mut pmp, 0, PMP_NAPOT << 3 | PMP_W, u32::MAX);
+        assert_eq!(pmp.read_cfg(0), (PMP_NAPOT << 3) as u32);
+    }
+
+    #[test]
+    fn unimplemented_entries_read_zero() {
+        let mut pmp = Pmp::new(2);
+        set(&mut pmp, 2, PMP_NAPOT << 3 | RWX, u32::MAX);
+        assert_eq!(pmp.read_addr(2), 0);
+        assert_eq!(pmp.read_cfg(0) >> 16, 0);
+    }
+}
@@ -0,0 +1,324 @@
+// RV32C compressed instruction expansion.
+//
+// Every 16-bit instruction is rewritten into its 32-bit base ISA equivalent so
+// `MiniRV32IMAState::step` only has one decoder to maintain. RV64/RV128-only
+// and D-extension encodings are reported as illegal.
+
+fn bits(c: u32, hi: u32, lo: u32) -> u32 {
+    (c >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+fn sign_extend(val: u32, bit_count: u32) -> u32 {
+    let shift = 32 - bit_count;
+    (((val << shift) as i32) >> shift) as u32
+}
+
+fn itype(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn stype(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    (((imm >> 5) & 0x7f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | ((imm & 0x1f) << 7)
+        | opcode
+}
+
+fn rtype(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn btype(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    (((imm >> 12) & 1) << 31)
+        | (((imm >> 5) & 0x3f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | (((imm >> 1) & 0xf) << 8)
+        | (((imm >> 11) & 1) << 7)
+        | 0x63
+}
+
+fn jtype(imm: u32, rd: u32) -> u32 {
+    (((imm >> 20) & 1) << 31)
+        | (((imm >> 1) & 0x3ff) << 21)
+        | (((imm >> 11) & 1) << 20)
+        | (((imm >> 12) & 0xff) << 12)
+        | (rd << 7)
+        | 0x6f
+}
+
+// CJ-format jump offset, shared by C.J and C.JAL.
+fn cj_offset(c: u32) -> u32 {
+    let imm = (bits(c, 12, 12) << 11)
+        | (bits(c, 11, 11) << 4)
+        | (bits(c, 10, 9) << 8)
+        | (bits(c, 8, 8) << 10)
+        | (bits(c, 7, 7) << 6)
+        | (bits(c, 6, 6) << 7)
+        | (bits(c, 5, 3) << 1)
+        | (bits(c, 2, 2) << 5);
+    sign_extend(imm, 12)
+}
+
+// CB-format branch offset, shared by C.BEQZ and C.BNEZ.
+fn cb_offset(c: u32) -> u32 {
+    let imm = (bits(c, 12, 12) << 8)
+        | (bits(c, 11, 10) << 3)
+        | (bits(c, 6, 5) << 6)
+        | (bits(c, 4, 3) << 1)
+        | (bits(c, 2, 2) << 5);
+    sign_extend(imm, 9)
+}
+
+/// Expands a 16-bit RVC instruction into the equivalent 32-bit instruction.
+/// Returns `None` for reserved or unsupported encodings (illegal instruction).
+pub fn expand_compressed(c: u16) -> Option<u32> {
+    let c = c as u32;
+    // Full register numbers and the x8..x15 "prime" registers.
+    let rd = bits(c, 11, 7);
+    let rs2 = bits(c, 6, 2);
+    let rdp = bits(c, 4, 2) + 8;
+    let rs1p = bits(c, 9, 7) + 8;
+    let imm6 = sign_extend((bits(c, 12, 12) << 5) | bits(c, 6, 2), 6);
+
+    match (c & 3, bits(c, 15, 13)) {
+        (0, 0) => {
+            // C.ADDI4SPN
+            let imm = (bits(c, 12, 11) << 4)
+                | (bits(c, 10, 7) << 6)
+                | (bits(c, 6, 6) << 2)
+                | (bits(c, 5, 5) << 3);
+            if imm == 0 {
+                return None; // Also covers the all-zero illegal instruction.
+            }
+            Some(itype(imm, 2, 0, rdp, 0x13))
+        }
+        (0, 2) | (0, 3) => {
+            // C.LW / C.FLW
+            let imm = (bits(c, 12, 10) << 3) | (bits(c, 6, 6) << 2) | (bits(c, 5, 5) << 6);
+            let opcode = if bits(c, 15, 13) == 2 { 0x03 } else { 0x07 };
+            Some(itype(imm, rs1p, 2, rdp, opcode))
+        }
+        (0, 6) | (0, 7) => {
+            // C.SW / C.FSW
+            let imm = (bits(c, 12, 10) << 3) | (bits(c, 6, 6) << 2) | (bits(c, 5, 5) << 6);
+            let opcode = if bits(c, 15, 13) == 6 { 0x23 } else { 0x27 };
+            Some(stype(imm, rdp, rs1p, 2, opcode))
+        }
+        (1, 0) => {
+            // C.ADDI (C.NOP when rd = 0)
+            Some(itype(imm6, rd, 0, rd, 0x13))
+        }
+        (1, 1) => {
+            // C.JAL (RV32 only)
+            Some(jtype(cj_offset(c), 1))
+        }
+        (1, 2) => {
+            // C.LI
+            Some(itype(imm6, 0, 0, rd, 0x13))
+        }
+        (1, 3) => {
+            if rd == 2 {
+                // C.ADDI16SP
+                let imm = (bits(c, 12, 12) << 9)
+                    | (bits(c, 6, 6) << 4)
+                    | (bits(c, 5, 5) << 6)
+                    | (bits(c, 4, 3) << 7)
+                    | (bits(c, 2, 2) << 5);
+                if imm == 0 {
+                    return None;
+                }
+                Some(itype(sign_extend(imm, 10), 2, 0, 2, 0x13))
+            } else {
+                // C.LUI
+                if imm6 == 0 {
+                    return None;
+                }
+                Some((imm6 << 12) | (rd << 7) | 0x37)
+            }
+        }
+        (1, 4) => {
+            let shamt = bits(c, 6, 2);
+            match bits(c, 11, 10) {
+                // C.SRLI / C.SRAI, shamt[5] must be zero on RV32.
+                0 if bits(c, 12, 12) == 0 => Some(itype(shamt, rs1p, 5, rs1p, 0x13)),
+                1 if bits(c, 12, 12) == 0 => Some(itype(0x400 | shamt, rs1p, 5, rs1p, 0x13)),
+                // C.ANDI
+                2 => Some(itype(imm6, rs1p, 7, rs1p, 0x13)),
+                // C.SUB / C.XOR / C.OR / C.AND
+                3 if bits(c, 12, 12) == 0 => match bits(c, 6, 5) {
+                    0 => Some(rtype(0x20, rdp, rs1p, 0, rs1p, 0x33)),
+                    1 => Some(rtype(0, rdp, rs1p, 4, rs1p, 0x33)),
+                    2 => Some(rtype(0, rdp, rs1p, 6, rs1p, 0x33)),
+                    _ => Some(rtype(0, rdp, rs1p, 7, rs1p, 0x33)),
+                },
+                _ => None,
+            }
+        }
+        (1, 5) => {
+            // C.J
+            Some(jtype(cj_offset(c), 0))
+        }
+        (1, 6) => {
+            // C.BEQZ
+            Some(btype(cb_offset(c), 0, rs1p, 0))
+        }
+        (1, 7) => {
+            // C.BNEZ
+            Some(btype(cb_offset(c), 0, rs1p, 1))
+        }
+        (2, 0) => {
+            // C.SLLI, shamt[5] must be zero on RV32.
+            if bits(c, 12, 12) != 0 {
+                return None;
+            }
+            Some(itype(rs2, rd, 1, rd, 0x13))
+        }
+        (2, 2) | (2, 3) => {
+            // C.LWSP / C.FLWSP
+            let imm = (bits(c, 12, 12) << 5) | (bits(c, 6, 4) << 2) | (bits(c, 3, 2) << 6);
+            if bits(c, 15, 13) == 2 {
+                if rd == 0 {
+                    return None;
+                }
+                Some(itype(imm, 2, 2, rd, 0x03))
+            } else {
+                Some(itype(imm, 2, 2, rd, 0x07))
+            }
+        }
+        (2, 4) => match (bits(c, 12, 12), rd, rs2) {
+            // C.JR
+            (0, 0, 0) => None,
+            (0, _, 0) => Some(itype(0, rd, 0, 0, 0x67)),
+            // C.MV
+            (0, _, _) => Some(rtype(0, rs2, 0, 0, rd, 0x33)),
+            // C.EBREAK
+            (_, 0, 0) => Some(0x00100073),
+            // C.JALR
+            (_, _, 0) => Some(itype(0, rd, 0, 1, 0x67)),
+            // C.ADD
+            (_, _, _) => Some(rtype(0, rs2, rd, 0, rd, 0x33)),
+        },
+        (2, 6) | (2, 7) => {
+            // C.SWSP / C.FSWSP
+            let imm = (bits(c, 12, 9) << 2) | (bits(c, 8, 7) << 6);
+            let opcode = if bits(c, 15, 13) == 6 { 0x23 } else { 0x27 };
+            Some(stype(imm, rs2, 2, 2, opcode))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expected expansions were cross-checked against the GNU/LLVM encodings.
+
+    #[test]
+    fn ciw_format() {
+        // c.addi4spn s0, sp, 1020: every nzuimm bit set.
+        assert_eq!(expand_compressed(0x1fe0), Some(0x3fc10413));
+    }
+
+    #[test]
+    fn cl_cs_formats() {
+        // c.lw a5, 124(a3) / c.sw a5, 64(a3)
+        assert_eq!(expand_compressed(0x5efc), Some(0x07c6a783));
+        assert_eq!(expand_compressed(0xc2bc), Some(0x04f6a023));
+        // c.flw fa0, 124(a3) / c.fsw fa0, 64(a3)
+        assert_eq!(expand_compressed(0x7ee8), Some(0x07c6a507));
+        assert_eq!(expand_compressed(0xe2a8), Some(0x04a6a027));
+    }
+
+    #[test]
+    fn ci_format() {
+        // c.nop, c.addi a0, -32, c.li t0, 31
+        assert_eq!(expand_compressed(0x0001), Some(0x00000013));
+        assert_eq!(expand_compressed(0x1501), Some(0xfe050513));
+        assert_eq!(expand_compressed(0x42fd), Some(0x01f00293));
+        // c.addi16sp sp, -512 / 496
+        assert_eq!(expand_compressed(0x7101), Some(0xe0010113));
+        assert_eq!(expand_compressed(0x617d), Some(0x1f010113));
+        // c.lui a1, 0xfffe0
+        assert_eq!(expand_compressed(0x7581), Some(0xfffe05b7));
+        // c.slli t1, 31
+        assert_eq!(expand_compressed(0x037e), Some(0x01f31313));
+        // c.lwsp ra, 252(sp) / c.flwsp fa0, 252(sp)
+        assert_eq!(expand_compressed(0x50fe), Some(0x0fc12083));
+        assert_eq!(expand_compressed(0x757e), Some(0x0fc12507));
+    }
+
+    #[test]
+    fn css_format() {
+        // c.swsp ra, 252(sp) / t2, 132(sp), c.fswsp fa0, 252(sp)
+        assert_eq!(expand_compressed(0xdf86), Some(0x0e112e23));
+        assert_eq!(expand_compressed(0xc31e), Some(0x08712223));
+        assert_eq!(expand_compressed(0xffaa), Some(0x0ea12e27));
+    }
+
+    #[test]
+    fn cj_format() {
+        // c.jal -2048 / 1366
+        assert_eq!(expand_compressed(0x3001), Some(0x801ff0ef));
+        assert_eq!(expand_compressed(0x2b99), Some(0x556000ef));
+        // c.j -2 / 2046
+        assert_eq!(expand_compressed(0xbffd), Some(0xfffff06f));
+        assert_eq!(expand_compressed(0xaffd), Some(0x7fe0006f));
+    }
+
+    #[test]
+    fn cb_format() {
+        // c.beqz a0, -256 / 170, c.bnez a1, 254
+        assert_eq!(expand_compressed(0xd101), Some(0xf00500e3));
+        assert_eq!(expand_compressed(0xc54d), Some(0x0a050563));
+        assert_eq!(expand_compressed(0xedfd), Some(0x0e059f63));
+        // c.srli s1, 31, c.srai s1, 1, c.andi a2, -1
+        assert_eq!(expand_compressed(0x80fd), Some(0x01f4d493));
+        assert_eq!(expand_compressed(0x8485), Some(0x4014d493));
+        assert_eq!(expand_compressed(0x9a7d), Some(0xfff67613));
+    }
+
+    #[test]
+    fn ca_format() {
+        // c.sub / c.xor / c.or / c.and s0, s1
+        assert_eq!(expand_compressed(0x8c05), Some(0x40940433));
+        assert_eq!(expand_compressed(0x8c25), Some(0x00944433));
+        assert_eq!(expand_compressed(0x8c45), Some(0x00946433));
+        assert_eq!(expand_compressed(0x8c65), Some(0x00947433));
+    }
+
+    #[test]
+    fn cr_format() {
+        // c.jr ra, c.mv a0, a1, c.ebreak, c.jalr t0, c.add a0, a1
+        assert_eq!(expand_compressed(0x8082), Some(0x00008067));
+        assert_eq!(expand_compressed(0x852e), Some(0x00b00533));
+        assert_eq!(expand_compressed(0x9002), Some(0x00100073));
+        assert_eq!(expand_compressed(0x9282), Some(0x000280e7));
+        assert_eq!(expand_compressed(0x952e), Some(0x00b50533));
+    }
+
+    #[test]
+    fn reserved_encodings() {
+        // All-zero parcel and c.addi4spn with nzuimm = 0.
+        assert_eq!(expand_compressed(0x0000), None);
+        assert_eq!(expand_compressed(0x0004), None);
+        // c.addi16sp and c.lui with a zero immediate.
+        assert_eq!(expand_compressed(0x6101), None);
+        assert_eq!(expand_compressed(0x6581), None);
+        // c.lwsp with rd = 0 and c.jr with rs1 = 0.
+        assert_eq!(expand_compressed(0x4002), None);
+        assert_eq!(expand_compressed(0x8002), None);
+        // Shifts with shamt[5] set are RV64 only.
+        assert_eq!(expand_compressed(0x9005), None);
+        assert_eq!(expand_compressed(0x1082), None);
+        // c.fld, c.fldsp, the reserved quadrant 0 slot and c.subw.
+        assert_eq!(expand_compressed(0x2000), None);
+        assert_eq!(expand_compressed(0x2002), None);
+        assert_eq!(expand_compressed(0x8000), None);
+        assert_eq!(expand_compressed(0x9c01), None);
+    }
+}
@@ -1,7 +1,95 @@
+use std::collections::BTreeMap;
+
+use crate::clic::{CLIC_SIZE, Clic};
+use crate::clint::{CLINT_BASE, CLINT_SIZE, Clint};
+use crate::custom::{CustomContext, CustomInsnFn, CustomInstruction, CustomOpcode, CustomOutcome};
+use crate::mmio::{MmioBus, MmioDevice};
+use crate::mmu::{
+    self, PAGE_SIZE, PTE_A, PTE_D, PTE_R, PTE_V, PTE_W, PTE_X, SATP_MODE_SV32, SATP_PPN, Tlb,
+};
+use crate::plic::{PLIC_BASE, PLIC_CONTEXT_M, PLIC_CONTEXT_S, PLIC_SIZE, Plic};
+use crate::pmp::Pmp;
+use crate::softfloat::{self, RoundingMode, SIGN};
+
+// Default memory map, the one uvm32 guests are linked for.
 pub const MINIRV32_RAM_IMAGE_OFFSET: u32 = 0x80000000;
-pub const MINI_RV32_RAM_SIZE: u32 = 0x00100000; // 1 MiB
 pub const UVM32_MEMORY_SIZE: u32 = 65536; // 64 KiB
 pub const UVM32_SYSCALL_HALT: u32 = 0x1000000;
+// Register holding the syscall number: a7, or t0 in the RV32E (ilp32e) ABI.
+const SYSCALL_REG: usize = 17;
+const SYSCALL_REG_E: usize = 5;
+
+// misa: MXL = 1 (XLEN=32) and one bit per single-letter extension. S and U
+// follow `IsaConfig::supervisor` and `IsaConfig::user`, X is only set once the
+// host registers a custom CSR or opcode.
+const MISA_MXL_32: u32 = 1 << 30;
+const MISA_A: u32 = 1 << 0;
+const MISA_C: u32 = 1 << 2;
+const MISA_E: u32 = 1 << 4;
+const MISA_F: u32 = 1 << 5;
+const MISA_I: u32 = 1 << 8;
+const MISA_M: u32 = 1 << 12;
+const MISA_S: u32 = 1 << 18;
+const MISA_U: u32 = 1 << 20;
+const MISA_X: u32 = 1 << 23;
+// Extensions a guest may toggle through misa when configured to.
+const MISA_TOGGLEABLE: u32 = MISA_A | MISA_C | MISA_F | MISA_M;
+
+// mip/mie bits.
+const MIP_SSIP: u32 = 1 << 1;
+const MIP_MSIP: u32 = 1 << 3;
+const MIP_STIP: u32 = 1 << 5;
+const MIP_MTIP: u32 = 1 << 7;
+const MIP_SEIP: u32 = 1 << 9;
+const MIP_MEIP: u32 = 1 << 11;
+const MIP_S_ALL: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+// mstatus bits.
+const MSTATUS_SIE: u32 = 1 << 1;
+const MSTATUS_MIE: u32 = 1 << 3;
+const MSTATUS_SPIE: u32 = 1 << 5;
+const MSTATUS_MPIE: u32 = 1 << 7;
+const MSTATUS_SPP: u32 = 1 << 8;
+const MSTATUS_MPP: u32 = 3 << 11;
+const MSTATUS_FS: u32 = 3 << 13;
+const MSTATUS_FS_INITIAL: u32 = 1 << 13;
+const MSTATUS_MPRV: u32 = 1 << 17;
+const MSTATUS_SUM: u32 = 1 << 18;
+const MSTATUS_MXR: u32 = 1 << 19;
+const MSTATUS_TVM: u32 = 1 << 20;
+const MSTATUS_TW: u32 = 1 << 21;
+const MSTATUS_TSR: u32 = 1 << 22;
+// Read-only summary of FS = Dirty.
+const MSTATUS_SD: u32 = 1 << 31;
+// The mstatus fields visible through sstatus. FS is writable only with F.
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+const SSTATUS_READ_MASK: u32 = SSTATUS_MASK | MSTATUS_FS | MSTATUS_SD;
+
+// mtvec and stvec MODE field. CLIC mode is only selectable in mtvec, and
+// only with a CLIC.
+const MTVEC_MODE: u32 = 3;
+const MTVEC_MODE_DIRECT: u32 = 0;
+const MTVEC_MODE_VECTORED: u32 = 1;
+const MTVEC_MODE_CLIC: u32 = 3;
+
+// mcause fields in CLIC mode. MPP and MPIE mirror mstatus.
+const MCAUSE_INTERRUPT: u32 = 1 << 31;
+const MCAUSE_MINHV: u32 = 1 << 30;
+const MCAUSE_MPP: u32 = 3 << 28;
+const MCAUSE_MPIE: u32 = 1 << 27;
+const MCAUSE_MPIL_SHIFT: u32 = 16;
+const MCAUSE_MPIL: u32 = 0xff << MCAUSE_MPIL_SHIFT;
+const MCAUSE_EXCCODE: u32 = 0xfff;
+// mintstatus.mil, the level of the running interrupt handler.
+const MINTSTATUS_MIL_SHIFT: u32 = 24;
+
+// Exceptions S-mode can handle: everything but ECALL from M-mode.
+const MEDELEG_MASK: u32 = 0xb3ff;
+
+// mcountinhibit bits, mcounteren uses the same layout.
+const COUNTER_CY: u32 = 1 << 0;
+const COUNTER_TM: u32 = 1 << 1;
+const COUNTER_IR: u32 = 1 << 2;
 
 fn minirv32_load4(ofs: u32, image: &[u8]) -> u32 {
     let offset = ofs as usize;
@@ -12,46 +100,24 @@ fn minirv32_load4(ofs: u32, image: &[u8]) -> u32 {
     let byte2 = image[offset + 2] as u32;
     let byte3 = image[offset + 3] as u32;
 
-    let ret: u32 = (byte0) | (byte1 << 8) | (byte2 << 16) | (byte3 << 24);
-    return ret;
-}
-
-fn minirv32_load1_signed(ofs: u32, image: &[u8]) -> i8 {
-    let offset = ofs as usize;
-    let byte = image[offset] as i8;
-    return byte;
+    (byte0) | (byte1 << 8) | (byte2 << 16) | (byte3 << 24)
 }
 
 fn minirv32_load1(ofs: u32, image: &[u8]) -> u8 {
     let offset = ofs as usize;
-    let byte = image[offset] as u8;
-    return byte;
+    image[offset]
 }
 
 fn minirv32_load2(ofs: u32, image: &[u8]) -> u16 {
     let offset = ofs as usize;
     let byte0 = image[offset] as u16;
     let byte1 = image[offset + 1] as u16;
-    let halfword = (byte0) | (byte1 << 8);
-    return halfword;
-}
-
-fn minirv32_load2_signed(ofs: u32, image: &[u8]) -> i16 {
-    let offset = ofs as usize;
-    let byte0 = image[offset] as u16;
-    let byte1 = image[offset + 1] as u16;
-    let halfword = (byte0) | (byte1 << 8);
-    return halfword as i16;
-}
-
-fn minirv32_mmio_range(n: u32) -> bool {
-    // Example MMIO range check (to be customized as needed)
-    0x10000000 <= n && n < 0x12000000
+    (byte0) | (byte1 << 8)
 }
 
 fn minirv32_store1(ofs: u32, val: u8, image: &mut [u8]) {
     let offset = ofs as usize;
-    image[offset] = (val & 0xff) as u8;
+    image[offset] = val;
 }
 
 fn minirv32_store2(ofs: u32, val: u16, image: &mut [u8]) {
@@ -68,6 +134,292 @@ fn minirv32_store4(ofs: u32, val: u32, image: &mut [u8]) {
     image[offset + 3] = ((val >> 24) & 0xff) as u8;
 }
 
+/// Synchronous exception causes, the discriminant is the mcause code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapCause {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+    EcallFromU = 8,
+    EcallFromS = 9,
+    EcallFromM = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl TrapCause {
+    pub fn mcause(self) -> u32 {
+        self as u32
+    }
+
+    /// The environment call cause for an ECALL made from `privilege`.
+    pub fn ecall_from(privilege: Privilege) -> Self {
+        match privilege {
+            Privilege::User => TrapCause::EcallFromU,
+            Privilege::Supervisor => TrapCause::EcallFromS,
+            Privilege::Machine => TrapCause::EcallFromM,
+        }
+    }
+
+    fn access_fault(access: AccessType) -> Self {
+        match access {
+            AccessType::Fetch => TrapCause::InstructionAccessFault,
+            AccessType::Load => TrapCause::LoadAccessFault,
+            AccessType::Store => TrapCause::StoreAccessFault,
+        }
+    }
+
+    fn page_fault(access: AccessType) -> Self {
+        match access {
+            AccessType::Fetch => TrapCause::InstructionPageFault,
+            AccessType::Load => TrapCause::LoadPageFault,
+            AccessType::Store => TrapCause::StorePageFault,
+        }
+    }
+
+    fn misaligned(access: AccessType) -> Self {
+        match access {
+            AccessType::Fetch => TrapCause::InstructionAddressMisaligned,
+            AccessType::Load => TrapCause::LoadAddressMisaligned,
+            AccessType::Store => TrapCause::StoreAddressMisaligned,
+        }
+    }
+
+    // Causes for which mtval holds the faulting address.
+    fn has_address(self) -> bool {
+        !matches!(
+            self,
+            TrapCause::IllegalInstruction
+                | TrapCause::Breakpoint
+                | TrapCause::EcallFromU
+                | TrapCause::EcallFromS
+                | TrapCause::EcallFromM
+        )
+    }
+}
+
+/// The kind of memory access, for PMP and page permission checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    Fetch,
+    Load,
+    /// Stores and AMOs.
+    Store,
+}
+
+/// Privilege levels, encoded as in mstatus.MPP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    fn from_bits(bits: u32) -> Self {
+        match bits & 3 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+/// Why `MiniRV32IMAState::step` returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepResult {
+    /// Every requested instruction ran. Interrupts taken along the way are
+    /// not reported, the guest handles them.
+    Retired(u32),
+    /// The guest executed ECALL, which has not been taken yet: the pc still
+    /// points at it. Handle the call and `increment_pc(4)`, or hand it to the
+    /// guest trap handler with `raise_exception`.
+    Ecall { privilege: Privilege },
+    /// The guest executed EBREAK, the pc still points at it.
+    Breakpoint,
+    /// The hart is waiting for an interrupt after a WFI. Further steps
+    /// return this without running anything until an enabled interrupt is
+    /// pending, so the host should let time pass: see `get_next_deadline`.
+    Wfi,
+    /// ECALL with `UVM32_SYSCALL_HALT` in a7 (t0 on RV32E), the pc still
+    /// points at it.
+    Halt,
+    /// Any other exception. It has already been delivered to the guest trap
+    /// handler (mepc/mcause/mtval set, pc at mtvec); hosts that treat guest
+    /// faults as fatal stop here, others simply keep stepping.
+    Fault {
+        cause: TrapCause,
+        mtval: u32,
+        pc: u32,
+    },
+}
+
+/// Why `MiniRV32IMAState::run` returned. Apart from `BudgetExhausted`, these
+/// mean the same as the `StepResult` variants of the same name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The whole budget was executed.
+    BudgetExhausted,
+    Ecall {
+        privilege: Privilege,
+    },
+    Breakpoint,
+    Wfi,
+    Halt,
+    Fault {
+        cause: TrapCause,
+        mtval: u32,
+        pc: u32,
+    },
+}
+
+/// What a call to `MiniRV32IMAState::run` did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunResult {
+    /// Instructions retired. ECALL, EBREAK and faulting instructions don't
+    /// retire, WFI does.
+    pub executed: u64,
+    /// The part of the budget left, `budget - executed`.
+    pub remaining: u64,
+    pub reason: StopReason,
+}
+
+clone_box_fn!(
+    /// Host callback producing the value of a custom CSR.
+    CsrReader: FnMut() -> u32
+);
+clone_box_fn!(
+    /// Host callback receiving a value written to a custom CSR.
+    CsrWriter: FnMut(u32)
+);
+
+pub type CsrReadFn = Box<dyn CsrReader>;
+pub type CsrWriteFn = Box<dyn CsrWriter>;
+
+#[derive(Clone)]
+struct CustomCsr {
+    read: CsrReadFn,
+    write: Option<CsrWriteFn>,
+}
+
+// How a Zicsr instruction modifies the CSR it accesses.
+#[derive(Clone, Copy)]
+enum CsrWrite {
+    Assign(u32), // CSRRW(I)
+    Set(u32),    // CSRRS(I)
+    Clear(u32),  // CSRRC(I)
+}
+
+impl CsrWrite {
+    fn apply(self, old: u32) -> u32 {
+        match self {
+            CsrWrite::Assign(val) => val,
+            CsrWrite::Set(bits) => old | bits,
+            CsrWrite::Clear(bits) => old & !bits,
+        }
+    }
+}
+
+// Applies `write` to a WARL register of which only the `writable` bits can
+// change, the others keep their value. Returns the old value.
+fn warl(reg: &mut u32, writable: u32, write: Option<CsrWrite>) -> u32 {
+    let old = *reg;
+    if let Some(write) = write {
+        *reg = (old & !writable) | (write.apply(old) & writable);
+    }
+    old
+}
+
+// Accesses the low or high half of a 64-bit counter. `counting` says whether
+// the counter is about to be incremented for the writing instruction.
+fn counter_half(counter: &mut u64, high: bool, write: Option<CsrWrite>, counting: bool) -> u32 {
+    let shift = if high { 32 } else { 0 };
+    let old = (*counter >> shift) as u32;
+    if let Some(write) = write {
+        let val = write.apply(old) as u64;
+        *counter = (*counter & !(0xffffffff << shift)) | (val << shift);
+        // The writing instruction still retires afterwards, which counts it.
+        if counting {
+            *counter = counter.wrapping_sub(1);
+        }
+    }
+    old
+}
+
+// Where a trap with `cause` enters through mtvec or stvec value `tvec`: in
+// vectored mode interrupts go to BASE + 4 * cause, all else goes to BASE.
+fn trap_vector(tvec: u32, cause: u32) -> u32 {
+    let base = tvec & !MTVEC_MODE;
+    if tvec & MTVEC_MODE == MTVEC_MODE_VECTORED && cause & MCAUSE_INTERRUPT != 0 {
+        base.wrapping_add(4 * (cause & !MCAUSE_INTERRUPT))
+    } else {
+        base
+    }
+}
+
+// The mtvec or stvec value a write of `val` leaves. MODE is WARL: writing a
+// reserved mode keeps the old one. The base of CLIC mode is 64-byte aligned.
+fn legal_tvec(old: u32, val: u32, clic: bool) -> u32 {
+    let mode = match val & MTVEC_MODE {
+        MTVEC_MODE_DIRECT | MTVEC_MODE_VECTORED => val & MTVEC_MODE,
+        MTVEC_MODE_CLIC if clic => MTVEC_MODE_CLIC,
+        _ => old & MTVEC_MODE,
+    };
+    if mode == MTVEC_MODE_CLIC {
+        (val & !0x3f) | mode
+    } else {
+        (val & !MTVEC_MODE) | mode
+    }
+}
+
+// Whether an OP or OP-IMM instruction has a funct7 the base ISA defines: 0x20
+// only selects SUB and SRA(I), and the shift immediates are 5 bits on RV32.
+fn base_alu_encoding(ir: u32) -> bool {
+    let is_reg = ir & 0x20 != 0;
+    let funct7 = ir >> 25;
+    match (ir >> 12) & 7 {
+        0 if is_reg => funct7 == 0 || funct7 == 0x20,
+        5 => funct7 == 0 || funct7 == 0x20,
+        1 => funct7 == 0,
+        _ => !is_reg || funct7 == 0,
+    }
+}
+
+// Whether `ir` names any of x16..x31, which don't exist on RV32E. Only the
+// rd/rs1/rs2 fields that hold x registers for the instruction are checked.
+fn uses_upper_x_registers(ir: u32) -> bool {
+    let funct3 = (ir >> 12) & 7;
+    let (rd, rs1, rs2) = match ir & 0x7f {
+        0x37 | 0x17 | 0x6f => (true, false, false),
+        0x67 | 0x03 | 0x13 => (true, true, false),
+        0x63 | 0x23 => (false, true, true),
+        // Custom opcodes are decoded with the R-type layout.
+        0x33 | 0x2f | 0x0b | 0x2b | 0x5b | 0x7b => (true, true, true),
+        // FLW/FSW: only the base address register.
+        0x07 | 0x27 => (false, true, false),
+        // The CSRxxI forms have an immediate in rs1, SFENCE.VMA uses rs1/rs2.
+        0x73 if funct3 == 0 => (true, true, true),
+        0x73 => (true, funct3 & 4 == 0, false),
+        // OP-FP: compares and FCVT.W(U).S/FMV.X.W/FCLASS.S write an x
+        // register, FCVT.S.W(U)/FMV.W.X read one.
+        0x53 => match ir >> 25 {
+            0x50 | 0x60 | 0x70 => (true, false, false),
+            0x68 | 0x78 => (false, true, false),
+            _ => (false, false, false),
+        },
+        _ => (false, false, false),
+    };
+    (rd && ir & (0x10 << 7) != 0)
+        || (rs1 && ir & (0x10 << 15) != 0)
+        || (rs2 && ir & (0x10 << 20) != 0)
+}
+
 pub struct RV32IRegisters {
     pub regs: [u32; 32],
     pub pc: u32,
@@ -84,11 +436,159 @@ pub struct RV32IRegisters {
     // Note: only a few bits are used.  (Machine = 3, User = 0)
     // Bits 0..1 = privilege.
     // Bit 2 = WFI (Wait for interrupt)
-    // Bit 3+ = Load/Store reservation LSBs.
     pub extraflags: u32,
 }
 
-#[derive(Clone, Default, Copy)]
+/// Extensions implemented by the hart. Instructions and CSRs of disabled
+/// extensions are illegal, and misa only reports the enabled ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IsaConfig {
+    /// RV32E base instead of RV32I: only x0..x15 exist and naming x16..x31
+    /// is illegal. ECALLs pass the syscall number in t0 instead of a7.
+    pub e: bool,
+    /// Integer multiplication and division.
+    pub m: bool,
+    /// Atomic instructions (LR/SC and AMOs).
+    pub a: bool,
+    /// Compressed instructions.
+    pub c: bool,
+    /// Single-precision floating point, requires Zicsr. mstatus.FS resets to
+    /// Initial so bare-metal guests can use it without enabling it first.
+    pub f: bool,
+    /// CSR instructions. Without them traps still work, but can't be set up.
+    pub zicsr: bool,
+    /// The cycle, time and instret CSRs (M-mode counters are unaffected).
+    pub zicntr: bool,
+    /// Address generation instructions (sh1add, sh2add, sh3add).
+    pub zba: bool,
+    /// Basic bit manipulation (andn, clz, min, rev8, rol, ...).
+    pub zbb: bool,
+    /// Single-bit instructions (bclr, bext, binv, bset).
+    pub zbs: bool,
+    /// S-mode, with its CSRs, trap delegation and Sv32. Requires `user`.
+    pub supervisor: bool,
+    /// U-mode. Without it the hart only runs in M-mode.
+    pub user: bool,
+    /// misa bits of enabled extensions that the guest may clear and set
+    /// again. Only M, A, C and F can be made writable, the rest are ignored.
+    pub misa_writable: u32,
+}
+
+impl Default for IsaConfig {
+    fn default() -> Self {
+        Self {
+            e: false,
+            m: true,
+            a: true,
+            c: true,
+            f: true,
+            zicsr: true,
+            zicntr: true,
+            zba: true,
+            zbb: true,
+            zbs: true,
+            supervisor: true,
+            user: true,
+            misa_writable: 0,
+        }
+    }
+}
+
+impl IsaConfig {
+    /// The misa value for this extension set. X is only reported once the
+    /// host registers a custom opcode or CSR.
+    pub fn misa(&self) -> u32 {
+        let base = if self.e { MISA_E } else { MISA_I };
+        let mut misa = MISA_MXL_32 | base;
+        for (enabled, bit) in [
+            (self.m, MISA_M),
+            (self.a, MISA_A),
+            (self.c, MISA_C),
+            (self.f, MISA_F),
+            (self.supervisor, MISA_S),
+            (self.user, MISA_U),
+        ] {
+            if enabled {
+                misa |= bit;
+            }
+        }
+        misa
+    }
+}
+
+/// What loads and stores do at addresses that aren't a multiple of their size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MisalignedPolicy {
+    /// Raise load/store address-misaligned with the address in mtval.
+    Trap,
+    /// Perform the access byte by byte, as hardware support would, and count
+    /// it in `MiniRV32IMAState::get_misaligned_count`. The access faults as a
+    /// whole before touching memory, and only RAM supports it; atomics always
+    /// trap.
+    Emulate,
+}
+
+/// Memory map and reset state of the emulated machine.
+///
+/// RAM is the `image` slice passed to `MiniRV32IMAState::step`, mapped at
+/// `ram_base`. The host must allocate at least `ram_size` bytes for it.
+#[derive(Clone, Copy, Debug)]
+pub struct MachineConfig {
+    pub ram_base: u32,
+    pub ram_size: u32,
+    /// Initial value of x2 (sp).
+    pub initial_sp: u32,
+    pub reset_pc: u32,
+    pub clint_base: u32,
+    pub plic_base: u32,
+    /// Where to map a CLIC, if the machine has one. It then also has the
+    /// mtvt, mnxti, mintstatus and mintthresh CSRs.
+    pub clic_base: Option<u32>,
+    /// Number of PMP entries (0..=64). With none, U-mode may access all memory.
+    pub pmp_entries: usize,
+    pub isa: IsaConfig,
+    pub misaligned: MisalignedPolicy,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self::with_ram(MINIRV32_RAM_IMAGE_OFFSET, UVM32_MEMORY_SIZE)
+    }
+}
+
+impl MachineConfig {
+    /// A machine with `ram_size` bytes of RAM at `ram_base`, resetting to the
+    /// start of RAM with the stack at its top.
+    pub fn with_ram(ram_base: u32, ram_size: u32) -> Self {
+        Self {
+            ram_base,
+            ram_size,
+            // https://projectf.io/posts/riscv-cheat-sheet/
+            // setup stack pointer
+            // la	sp, _sstack
+            // addi	sp,sp,-16
+            initial_sp: (ram_base.wrapping_add(ram_size) & !0xF).wrapping_sub(16), // 16 byte align stack
+            reset_pc: ram_base,
+            clint_base: CLINT_BASE,
+            plic_base: PLIC_BASE,
+            clic_base: None,
+            pmp_entries: 16,
+            isa: IsaConfig::default(),
+            misaligned: MisalignedPolicy::Emulate,
+        }
+    }
+
+    /// Like `with_ram`, for an RV32E hart. The ilp32e ABI only keeps the
+    /// stack 4-byte aligned.
+    pub fn with_ram_rv32e(ram_base: u32, ram_size: u32) -> Self {
+        let mut config = Self::with_ram(ram_base, ram_size);
+        config.initial_sp = (ram_base.wrapping_add(ram_size) & !3).wrapping_sub(4);
+        config.isa.e = true;
+        config
+    }
+}
+
+#[derive(Clone)]
 pub struct MiniRV32IMAState {
     regs: [u32; 32],
     pc: u32,
@@ -105,17 +605,80 @@ pub struct MiniRV32IMAState {
     // Note: only a few bits are used.  (Machine = 3, User = 0)
     // Bits 0..1 = privilege.
     // Bit 2 = WFI (Wait for interrupt)
-    // Bit 3+ = Load/Store reservation LSBs.
     extraflags: u32,
+    // Physical address reserved by the last LR.W, until an SC.W, a store to
+    // that word or a trap drops it.
+    reservation: Option<u32>,
+    misa: u32,
+    // F extension: f0..f31 as raw binary32 bits and fcsr (frm << 5 | fflags).
+    fregs: [u32; 32],
+    fcsr: u32,
+    mcounteren: u32,
+    mcountinhibit: u32,
+    medeleg: u32,
+    mideleg: u32,
+
+    // S-mode trap CSRs and address translation. sstatus, sie and sip are
+    // views of the M-mode registers.
+    sscratch: u32,
+    stvec: u32,
+    sepc: u32,
+    scause: u32,
+    stval: u32,
+    scounteren: u32,
+    satp: u32,
+    tlb: Tlb,
+    config: MachineConfig,
+
+    // Zicntr counters. `time` is `time_source()` (or `ticks`, the cycles
+    // elapsed since reset, when there is none) plus `time_offset`. Unlike
+    // mcycle, `ticks` can't be written or inhibited by the guest.
+    cycle: u64,
+    instret: u64,
+    ticks: u64,
+    time_offset: u64,
+    // Instructions retired since reset, the guest can't change it.
+    retired: u64,
+    time_source: Option<fn() -> u64>,
+    clint: Clint,
+    plic: Plic,
+    // CLIC and its CSRs, mintstatus only holds mil.
+    clic: Option<Clic>,
+    mtvt: u32,
+    mintstatus: u32,
+    mintthresh: u32,
+    pmp: Pmp,
+    mmio: MmioBus,
+    custom_csrs: BTreeMap<u32, CustomCsr>,
+    custom_opcodes: [Option<CustomInsnFn>; 4],
+    misaligned_accesses: u64,
     callback_on_trap: Option<fn(u32)>,
 }
 
 impl MiniRV32IMAState {
+    /// A machine with the default (uvm32) memory map.
     pub fn new(callback_on_trap: Option<fn(u32)>) -> Self {
+        Self::with_config(MachineConfig::default(), callback_on_trap)
+    }
+
+    pub fn with_config(config: MachineConfig, callback_on_trap: Option<fn(u32)>) -> Self {
+        assert!(
+            config.ram_base as u64 + config.ram_size as u64 <= 1 << 32,
+            "RAM must not wrap around the address space"
+        );
+        assert!(
+            !config.isa.f || config.isa.zicsr,
+            "the F extension requires Zicsr"
+        );
+        assert!(
+            !config.isa.supervisor || config.isa.user,
+            "S-mode requires U-mode"
+        );
         let mut me = Self {
             regs: [0; 32],
-            pc: MINIRV32_RAM_IMAGE_OFFSET,
-            mstatus: 0,
+            pc: config.reset_pc,
+            mstatus: (if config.isa.f { MSTATUS_FS_INITIAL } else { 0 })
+                | if config.isa.user { 0 } else { MSTATUS_MPP },
             mscratch: 0,
             mtvec: 0,
             mie: 0,
@@ -124,16 +687,46 @@ impl MiniRV32IMAState {
             mtval: 0,
             mcause: 0,
             extraflags: 3,
+            reservation: None,
+            misa: config.isa.misa(),
+            fregs: [0; 32],
+            fcsr: 0,
+            mcounteren: 0,
+            mcountinhibit: 0,
+            medeleg: 0,
+            mideleg: 0,
+            sscratch: 0,
+            stvec: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            scounteren: 0,
+            satp: 0,
+            tlb: Tlb::default(),
+            config,
+            cycle: 0,
+            instret: 0,
+            ticks: 0,
+            time_offset: 0,
+            retired: 0,
+            time_source: None,
+            clint: Clint::new(config.clint_base),
+            plic: Plic::new(config.plic_base),
+            clic: config.clic_base.map(Clic::new),
+            mtvt: 0,
+            mintstatus: 0,
+            mintthresh: 0,
+            pmp: Pmp::new(config.pmp_entries),
+            mmio: MmioBus::default(),
+            custom_csrs: BTreeMap::new(),
+            custom_opcodes: Default::default(),
+            misaligned_accesses: 0,
             callback_on_trap,
         };
 
-        // https://projectf.io/posts/riscv-cheat-sheet/
-        // setup stack pointer
-        // la	sp, _sstack
-        // addi	sp,sp,-16
-        me.regs[2] = ((MINIRV32_RAM_IMAGE_OFFSET + UVM32_MEMORY_SIZE) & !0xF) - 16; // 16 byte align stack
+        me.regs[2] = config.initial_sp;
         me.callback_on_trap = callback_on_trap;
-        return me;
+        me
     }
 
     pub fn get_state(&self) -> RV32IRegisters {
@@ -152,10 +745,47 @@ impl MiniRV32IMAState {
         }
     }
 
+    pub fn get_config(&self) -> &MachineConfig {
+        &self.config
+    }
+
     pub fn get_reg(&self, regnum: usize) -> u32 {
         self.regs[regnum]
     }
 
+    /// The syscall number of a pending ECALL: a7, or t0 on RV32E.
+    pub fn get_syscall_number(&self) -> u32 {
+        if self.config.isa.e {
+            self.regs[SYSCALL_REG_E]
+        } else {
+            self.regs[SYSCALL_REG]
+        }
+    }
+
+    /// Sets register x`regnum`, writes to x0 are ignored.
+    pub fn set_reg(&mut self, regnum: usize, val: u32) {
+        if regnum != 0 {
+            self.regs[regnum] = val;
+        }
+    }
+
+    /// Raw bits of register f`regnum`.
+    pub fn get_freg(&self, regnum: usize) -> u32 {
+        self.fregs[regnum]
+    }
+
+    pub fn set_freg(&mut self, regnum: usize, val: u32) {
+        self.fregs[regnum] = val;
+    }
+
+    pub fn get_fcsr(&self) -> u32 {
+        self.fcsr
+    }
+
+    pub fn get_misa(&self) -> u32 {
+        self.misa
+    }
+
     pub fn get_pc(&self) -> u32 {
         self.pc
     }
@@ -176,551 +806,3076 @@ impl MiniRV32IMAState {
         self.pc = self.pc.wrapping_add(delta);
     }
 
-    pub fn step(&mut self, image: &mut [u8], _v_proc_address: u32, count: i32) -> i32 {
-        let mut trap: u32 = 0;
-        let mut rval: u32;
-        let mut pc: u32 = self.pc;
-        rval = 0;
-        for _icount in 0..count {
-            let ir: u32;
-            rval = 0;
+    pub fn get_privilege(&self) -> Privilege {
+        Privilege::from_bits(self.extraflags)
+    }
 
-            let ofs_pc: u32 = pc.wrapping_sub(MINIRV32_RAM_IMAGE_OFFSET);
+    /// Takes exception `cause` at the current pc, exactly as if the guest had
+    /// raised it: the guest trap handler runs on the next step.
+    pub fn raise_exception(&mut self, cause: TrapCause, mtval: u32) {
+        self.take_trap(cause.mcause(), mtval);
+    }
 
-            if ofs_pc >= MINI_RV32_RAM_SIZE {
-                trap = 1 + 1; // Handle access violation on instruction read.
-                break;
-            } else if ofs_pc & 3 != 0 {
-                trap = 1 + 0; //Handle PC-misaligned access
-                break;
-            } else {
-                ir = minirv32_load4(ofs_pc, image);
-                let mut rdid: u32 = (ir >> 7) & 0x1f;
+    // Enters the M-mode trap handler, or the S-mode one if the trap is
+    // delegated, with the current pc as the return address.
+    fn take_trap(&mut self, mcause: u32, mtval: u32) {
+        // callback to notify trap
+        if let Some(callback) = self.callback_on_trap {
+            callback(mcause);
+        }
+        self.reservation = None;
+
+        // Traps from S- and U-mode go to S-mode if M-mode delegated them.
+        let privilege = self.extraflags & 3;
+        let delegated = if mcause & 0x80000000 != 0 {
+            self.mideleg
+        } else {
+            self.medeleg
+        };
+        let clic_interrupt = self.clic_mode() && mcause & MCAUSE_INTERRUPT != 0;
+        if privilege != 3 && !clic_interrupt && delegated & (1 << (mcause & 0x1f)) != 0 {
+            self.scause = mcause;
+            self.stval = mtval;
+            self.sepc = self.pc;
+            let sie = self.mstatus & MSTATUS_SIE;
+            self.mstatus = (self.mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP))
+                | (sie << 4)
+                | (privilege << 8);
+            self.pc = trap_vector(self.stvec, mcause);
+            self.extraflags = (self.extraflags & !3) | 1;
+            return;
+        }
 
-                match ir & 0x7f {
-                    0x37 => {
-                        // LUI (0b0110111)
-                        rval = ir & 0xfffff000;
-                    }
-                    0x17 => {
-                        // AUIPC (0b0010111)
-                        rval = pc + (ir & 0xfffff000);
-                    }
-                    0x6F => {
-                        // JAL (0b1101111)
-                        let mut reladdy: u32 = ((ir & 0x80000000) >> 11)
-                            | ((ir & 0x7fe00000) >> 20)
-                            | ((ir & 0x00100000) >> 9)
-                            | (ir & 0x000ff000);
-
-                        if (reladdy & 0x00100000) != 0 {
-                            reladdy |= 0xffe00000; // Sign extension.
-                        }
-                        rval = pc + 4;
-                        pc = pc.wrapping_add(reladdy as u32).wrapping_sub(4);
+        self.mcause = mcause;
+        self.mtval = mtval;
+        self.mepc = self.pc; //TRICKY: The kernel advances mepc automatically.
+        //CSR( mstatus ) & 8 = MIE, & 0x80 = MPIE
+        // On an interrupt, the system moves current MIE into MPIE and the
+        // current privilege into MPP. The other mstatus fields are kept.
+        let mie = self.mstatus & MSTATUS_MIE;
+        self.mstatus = (self.mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP))
+            | (mie << 4)
+            | ((self.extraflags & 3) << 11);
+        self.pc = trap_vector(self.mtvec, mcause);
+
+        // In CLIC mode mcause also saves the interrupted level, and an
+        // interrupt raises the level to its own.
+        if self.clic_mode() {
+            self.pc = self.mtvec & !0x3f;
+            self.mcause |= self.interrupt_level() << MCAUSE_MPIL_SHIFT;
+            if clic_interrupt {
+                let level = self.clic.as_ref().map_or(0, |clic| {
+                    clic.interrupt_level(mcause & MCAUSE_EXCCODE) as u32
+                });
+                self.set_interrupt_level(level);
+            }
+        }
+
+        // If trapping, always enter machine mode.
+        self.extraflags |= 3;
+    }
+
+    // Reports an exception raised by the instruction at the current pc.
+    // ECALL and EBREAK are left pending for the host, everything else is
+    // delivered to the guest.
+    fn exception(&mut self, cause: TrapCause, mtval: u32) -> StepResult {
+        match cause {
+            TrapCause::EcallFromU | TrapCause::EcallFromS | TrapCause::EcallFromM => {
+                if self.get_syscall_number() == UVM32_SYSCALL_HALT {
+                    StepResult::Halt
+                } else {
+                    StepResult::Ecall {
+                        privilege: self.get_privilege(),
                     }
+                }
+            }
+            TrapCause::Breakpoint => StepResult::Breakpoint,
+            _ => {
+                let pc = self.pc;
+                self.raise_exception(cause, mtval);
+                StepResult::Fault { cause, mtval, pc }
+            }
+        }
+    }
 
-                    0x67 => {
-                        // JALR (0b1100111)
+    // Fetches the raw instruction at `pc` with its length (2 for compressed
+    // instructions), or the exception and mtval it raises.
+    fn fetch(&mut self, image: &mut [u8], pc: u32) -> Result<(u32, u32), (TrapCause, u32)> {
+        let compressed = self.misa & MISA_C != 0;
+        // With C, instructions are only required to be 2-byte aligned.
+        let pc_align_mask = if compressed { 1 } else { 3 };
+        if pc & pc_align_mask != 0 {
+            //Handle PC-misaligned access
+            return Err((TrapCause::InstructionAddressMisaligned, pc));
+        }
 
-                        let imm: u32 = ir >> 20;
+        // Fetch in halfwords, a 32-bit instruction may straddle a word or
+        // page boundary. A fault on the upper half reports its address.
+        // Without C every instruction is 32 bits, so a parcel that would be
+        // compressed is the low half of an illegal instruction.
+        let lo = self.fetch_half(image, pc)?;
+        if compressed && lo & 3 != 3 {
+            Ok((lo, 2))
+        } else {
+            let hi = self.fetch_half(image, pc.wrapping_add(2))?;
+            Ok((lo | (hi << 16), 4))
+        }
+    }
 
-                        let mut ext = 0;
-                        if (imm & 0x800) != 0 {
-                            ext = 0xfffff000;
-                        }
+    // Without C, jumps and taken branches to a target that is not 4-byte
+    // aligned trap on the jump itself, with the target in mtval.
+    fn jump_misaligned(&self, target: u32) -> bool {
+        self.misa & MISA_C == 0 && target & 3 != 0
+    }
 
-                        let imm_se: u32 = imm | ext;
-                        rval = pc + 4;
-                        // #define REG( x ) state->regs[x]
-                        let reg_idx = (ir >> 15) & 0x1f;
-                        let reg_val = self.regs[reg_idx as usize];
-                        pc = (reg_val.wrapping_add(imm_se)) & !1;
-                        pc = pc.wrapping_sub(4);
+    fn fetch_half(&mut self, image: &mut [u8], vaddr: u32) -> Result<u32, (TrapCause, u32)> {
+        let paddr = self
+            .translate(image, vaddr, 2, AccessType::Fetch)
+            .map_err(|cause| (cause, vaddr))?;
+        match self.ram_offset(image, paddr as u64, 2) {
+            Some(ofs) if self.pmp_allows(paddr, 2, AccessType::Fetch) => {
+                Ok(minirv32_load2(ofs, image) as u32)
+            }
+            // Instructions can only be fetched from RAM.
+            _ => Err((TrapCause::InstructionAccessFault, vaddr)),
+        }
+    }
 
-                        /*pc = ( (REG( (ir >> 15) & 0x1f ) + imm_se) & ~1) - 4;*/
-                    }
+    /// Enables or disables the C (compressed instructions) extension.
+    /// It is enabled by default.
+    pub fn set_compressed(&mut self, enabled: bool) {
+        self.config.isa.c = enabled;
+        if enabled {
+            self.misa |= MISA_C;
+        } else {
+            self.misa &= !MISA_C;
+        }
+    }
 
-                    0x63 => {
-                        // Branch (0b1100011)
+    /// Sets the clock that feeds the `time` CSR, in ticks of the host's choosing.
+    /// With `None` (the default) time advances by one tick per cycle, which
+    /// keeps runs deterministic.
+    pub fn set_time_source(&mut self, time_source: Option<fn() -> u64>) {
+        let now = self.get_time();
+        self.time_source = time_source;
+        self.time_offset = 0;
+        self.time_offset = now.wrapping_sub(self.get_time());
+    }
 
-                        let mut immm4: u32 = ((ir & 0xf00) >> 7)
-                            | ((ir & 0x7e000000) >> 20)
-                            | ((ir & 0x80) << 4)
-                            | ((ir >> 31) << 12);
-                        if (immm4 & 0x1000) != 0 {
-                            immm4 |= 0xffffe000;
-                        }
-                        let reg_idx1 = (ir >> 15) & 0x1f;
-                        let reg_idx2 = (ir >> 20) & 0x1f;
-                        let rs1: i32 = self.regs[reg_idx1 as usize] as i32;
-                        let rs2: i32 = self.regs[reg_idx2 as usize] as i32;
-                        immm4 = pc.wrapping_add(immm4).wrapping_sub(4);
+    pub fn get_time(&self) -> u64 {
+        let base = match self.time_source {
+            Some(source) => source(),
+            None => self.ticks,
+        };
+        base.wrapping_add(self.time_offset)
+    }
 
-                        rdid = 0;
-                        match (ir >> 12) & 0x7 {
-                            // BEQ, BNE, BLT, BGE, BLTU, BGEU
-                            0 => {
-                                if rs1 == rs2 {
-                                    pc = immm4;
-                                }
-                            }
-                            1 => {
-                                if rs1 != rs2 {
-                                    pc = immm4;
-                                }
-                            }
-                            4 => {
-                                if rs1 < rs2 {
-                                    pc = immm4;
-                                }
-                            }
-                            5 => {
-                                if rs1 >= rs2 {
-                                    pc = immm4;
-                                }
-                            } //BGE
-                            6 => {
-                                if (rs1 as u32) < (rs2 as u32) {
-                                    pc = immm4;
-                                }
-                            } //BLTU
-                            7 => {
-                                if (rs1 as u32) >= (rs2 as u32) {
-                                    pc = immm4;
-                                }
-                            } //BGEU
-                            _ => {
-                                trap = 2 + 1;
-                            }
-                        }
-                    }
+    /// Sets the current time (CLINT mtime) without changing the time source.
+    pub fn set_time(&mut self, time: u64) {
+        self.time_offset = self
+            .time_offset
+            .wrapping_add(time.wrapping_sub(self.get_time()));
+    }
 
-                    0x03 => {
-                        // Load (0b0000011)
-                        let reg_idx1 = (ir >> 15) & 0x1f;
-                        let rs1: u32 = self.regs[reg_idx1 as usize];
-                        let imm: u32 = ir >> 20;
-                        let imm_se: u32 = if (imm & 0x800) != 0 {
-                            imm | 0xfffff000
-                        } else {
-                            imm
-                        };
-                        let mut rsval: u32 = rs1.wrapping_add(imm_se);
-                        rsval = rsval.wrapping_sub(MINIRV32_RAM_IMAGE_OFFSET);
-                        if rsval >= MINI_RV32_RAM_SIZE - 3 {
-                            rsval = rsval.wrapping_add(MINIRV32_RAM_IMAGE_OFFSET);
-                            if minirv32_mmio_range(rsval)
-                            // UART, CLNT
-                            {
-                                todo!("Peding to do memory-mapped I/O handling");
-                                // MINIRV32_HANDLE_MEM_LOAD_CONTROL( rsval, rval );
-                            } else {
-                                trap = 5 + 1;
-                                rval = rsval;
-                            }
-                        } else {
-                            match (ir >> 12) & 0x7 {
-                                //LB, LH, LW, LBU, LHU
-                                0 => {
-                                    rval = minirv32_load1_signed(rsval, image) as u32;
-                                }
-                                1 => {
-                                    rval = minirv32_load2_signed(rsval, image) as u32;
-                                }
-                                2 => {
-                                    rval = minirv32_load4(rsval, image) as u32;
-                                }
-                                4 => {
-                                    rval = minirv32_load1(rsval, image) as u32;
-                                }
-                                5 => {
-                                    rval = minirv32_load2(rsval, image) as u32;
-                                }
-                                _ => {
-                                    trap = 2 + 1;
-                                }
-                            }
-                        }
-                    }
+    pub fn get_clint(&self) -> &Clint {
+        &self.clint
+    }
 
-                    0x23 => {
-                        // Store 0b0100011
-                        let reg1 = (ir >> 15) & 0x1f;
-                        let reg2 = (ir >> 20) & 0x1f;
+    pub fn get_plic(&self) -> &Plic {
+        &self.plic
+    }
 
-                        let rs1: u32 = self.regs[reg1 as usize];
-                        let rs2: u32 = self.regs[reg2 as usize];
-                        let mut addy: u32 = ((ir >> 7) & 0x1f) | ((ir & 0xfe000000) >> 20);
+    /// Drives external interrupt line `irq` (1..PLIC_SOURCES) of the PLIC
+    /// high. The guest takes it on the next step if the PLIC routes it to an
+    /// enabled MEIP or SEIP.
+    pub fn raise_irq(&mut self, irq: u32) {
+        self.plic.set_level(irq, true);
+    }
 
-                        if addy & 0x800 != 0 {
-                            addy |= 0xfffff000;
-                        }
-                        // addy += rs1 - MINIRV32_RAM_IMAGE_OFFSET;
-                        addy = addy.wrapping_add(rs1);
-                        addy = addy.wrapping_sub(MINIRV32_RAM_IMAGE_OFFSET);
-                        rdid = 0;
+    /// Drives external interrupt line `irq` low again.
+    pub fn lower_irq(&mut self, irq: u32) {
+        self.plic.set_level(irq, false);
+    }
 
-                        if addy >= MINI_RV32_RAM_SIZE - 3 {
-                            addy += MINIRV32_RAM_IMAGE_OFFSET;
-                            if minirv32_mmio_range(addy) {
-                                todo!("Peding to do memory-mapped I/O handling");
-                                //MINIRV32_HANDLE_MEM_STORE_CONTROL( addy, rs2 );
-                            } else {
-                                trap = 7 + 1; // Store access fault.
-                                rval = addy;
-                            }
-                        } else {
-                            match (ir >> 12) & 0x7 {
-                                //SB, SH, SW
-                                0 => minirv32_store1(addy, rs2 as u8, image),
-                                1 => minirv32_store2(addy, rs2 as u16, image),
-                                2 => minirv32_store4(addy, rs2 as u32, image),
-                                _ => trap = 2 + 1,
-                            }
-                        }
-                    }
+    pub fn get_clic(&self) -> Option<&Clic> {
+        self.clic.as_ref()
+    }
 
-                    0x13 | 0x33 => {
-                        // Op-immediate 0b0010011
-                        // Op           0b0110011
-                        let mut imm: u32 = ir >> 20;
-                        let mask = if imm & 0x800 != 0 { 0xfffff000 } else { 0 };
-                        imm = imm | mask;
-                        let reg = (ir >> 15) & 0x1f;
-                        let rs1 = self.regs[reg as usize];
-                        let reg2 = imm & 0x1f;
-
-                        let is_reg = (!!(ir & 0x20)) != 0;
-                        let rs2 = if is_reg {
-                            self.regs[reg2 as usize]
-                        } else {
-                            imm
-                        };
+    /// Drives the input of CLIC interrupt `id` (0..CLIC_INTERRUPTS) high.
+    /// Panics if the machine has no CLIC.
+    pub fn raise_clic_irq(&mut self, id: u32) {
+        self.clic
+            .as_mut()
+            .expect("no CLIC configured")
+            .set_level(id, true);
+    }
 
-                        if is_reg && (ir & 0x02000000 != 0) {
-                            match (ir >> 12) & 7 {
-                                //0x02000000 = RV32M
-                                0 => {
-                                    rval = rs1 * rs2;
-                                    // MUL
-                                }
-                                1 => {
-                                    rval = ((rs1 as i32).wrapping_div(rs2 as i32)) as u32;
-                                    // MULH
-                                }
-                                2 => {
-                                    rval = ((rs1 as i32).wrapping_div(rs2 as i32)) as u32;
-                                    // MULHSU
-                                }
-                                3 => {
-                                    rval = ((rs1 as u32).wrapping_div(rs2 as u32)) as u32;
-                                    // MULHU
-                                }
-                                4 => {
-                                    rval = ((rs1 as i32).wrapping_rem(rs2 as i32)) as u32;
-                                    // DIV
-                                }
-                                5 => {
-                                    rval = ((rs1 as u32).wrapping_rem(rs2 as u32)) as u32;
-                                    // DIVU
-                                }
-                                6 => {
-                                    rval = ((rs1 as i32).wrapping_rem(rs2 as i32)) as u32;
-                                    // REM
-                                }
-                                7 => {
-                                    rval = ((rs1 as u32).wrapping_rem(rs2 as u32)) as u32;
-                                    // REMU
-                                }
-                                _ => {
-                                    trap = 2 + 2;
-                                }
-                            }
-                        } else {
-                            match ir >> 12 & 7 {
-                                0 => {
-                                    rval = if is_reg && (ir & 0x40000000) != 0 {
-                                        rs1 - rs2
-                                    } else {
-                                        //ignore overflow
-                                        rs1.wrapping_add(rs2)
-                                    };
-                                    // ADD/SUB
-                                }
-                                1 => {
-                                    rval = rs1.wrapping_shl(rs2 & 0x1f);
-                                    // SLL
-                                }
-                                2 => {
-                                    rval = ((rs1 as i32).wrapping_shr((rs2 & 0x1f) as u32)) as u32;
-                                    // SLT
-                                }
-                                3 => {
-                                    rval = if (rs1 as u32) < (rs2 as u32) { 1 } else { 0 };
-                                    // SLTU
-                                }
-                                4 => {
-                                    rval = rs1 ^ rs2;
-                                    // XOR
-                                }
-                                5 => {
-                                    rval = if (ir & 0x40000000) != 0 {
-                                        ((rs1 as i32).wrapping_shr((rs2 & 0x1f) as u32)) as u32
-                                    } else {
-                                        rs1.wrapping_shr(rs2 & 0x1f)
-                                    };
-                                    // SRL/SRA
-                                }
-                                6 => {
-                                    rval = rs1 | rs2;
-                                    // OR
-                                }
-                                7 => {
-                                    rval = rs1 & rs2;
-                                    // AND
-                                }
-                                _ => {
-                                    trap = 2 + 2; // Illegal instruction
-                                }
-                            }
-                        }
-                    }
+    /// Drives the input of CLIC interrupt `id` low again.
+    pub fn lower_clic_irq(&mut self, id: u32) {
+        self.clic
+            .as_mut()
+            .expect("no CLIC configured")
+            .set_level(id, false);
+    }
 
-                    0x0f => {
-                        // 0b0001111
-                        rdid = 0; // fencetype = (ir >> 12) & 0b111; We ignore fences in this impl.
-                    }
+    // Whether mtvec selects CLIC interrupt handling. mie and mip are then
+    // unused, the CLIC decides which interrupt to take.
+    fn clic_mode(&self) -> bool {
+        self.clic.is_some() && self.mtvec & MTVEC_MODE == MTVEC_MODE_CLIC
+    }
 
-                    0x73 => {
-                        // Zifencei+Zicsr  (0b1110011)
-                        let csrno = ir >> 20;
-                        let microop = (ir >> 12) & 0x7;
-                        if microop & 3 != 0 {
-                            // It's a Zicsr function.
-                            let rs1imm: u32 = (ir >> 15) & 0x1f;
-                            let rs1 = self.regs[rs1imm as usize];
-                            let mut writeval = rs1;
-                            match csrno {
-                                0x300 => {
-                                    rval = self.mstatus;
-                                }
-                                0x301 => {
-                                    rval = 0x40401101;
-                                    //misa (XLEN=32, IMA+X)
-                                }
-                                0x304 => {
-                                    rval = self.mie;
-                                }
-                                0x305 => {
-                                    rval = self.mtvec;
-                                }
-                                0x340 => {
-                                    rval = self.mscratch;
-                                }
-                                0x341 => {
-                                    rval = self.mepc;
-                                }
-                                0x342 => {
-                                    rval = self.mcause;
-                                }
-                                0x343 => {
-                                    rval = self.mtval;
-                                }
-                                0x344 => {
-                                    rval = self.mip;
-                                }
-                                0xC00 => {
-                                    //rval = self.cycle;
-                                    todo!("CSR not implemented: {:#x}", csrno);
-                                }
-                                0xf11 => {
-                                    //vendor id
-                                    rval = 0xff0ff0ff;
-                                }
-                                _ => {
-                                    // MINIRV32_OTHERCSR_READ( csrno, rval );
-                                    todo!("CSR not implemented: {:#x}", csrno);
-                                }
-                            }
+    // The level of the running interrupt handler, mintstatus.mil.
+    fn interrupt_level(&self) -> u32 {
+        self.mintstatus >> MINTSTATUS_MIL_SHIFT
+    }
 
-                            match microop {
-                                1 => {
-                                    //CSRRW
-                                    writeval = rs1;
-                                }
-                                2 => {
-                                    //CSRRS
-                                    writeval = rval | rs1;
-                                }
-                                3 => {
-                                    //CSRRC
-                                    writeval = rval & (!rs1);
-                                }
-                                5 => {
-                                    //CSRRWI
-                                    writeval = rs1imm;
-                                }
-                                6 => {
-                                    //CSRRSI
-                                    writeval = rval | rs1imm;
-                                }
-                                7 => {
-                                    //CSRRCI
-                                    writeval = rval & (!rs1imm);
-                                }
-                                _ => {
-                                    trap = 2 + 2; // Illegal instruction
-                                }
-                            }
+    fn set_interrupt_level(&mut self, level: u32) {
+        self.mintstatus = level << MINTSTATUS_MIL_SHIFT;
+    }
 
-                            match csrno {
-                                0x340 => {
-                                    self.mscratch = writeval;
-                                }
-                                0x305 => {
-                                    self.mtvec = writeval;
-                                }
-                                0x304 => {
-                                    self.mie = writeval;
-                                }
-                                0x344 => {
-                                    self.mip = writeval;
-                                }
-                                0x341 => {
-                                    self.mepc = writeval;
-                                }
-                                0x342 => {
-                                    self.mcause = writeval;
-                                }
-                                0x343 => {
-                                    self.mtval = writeval;
-                                }
-                                0x300 => {
-                                    self.mstatus = writeval;
+    // The CLIC interrupt to take above level `threshold` and its level.
+    fn clic_interrupt(&self, threshold: u32) -> Option<(u32, u32)> {
+        let clic = self.clic.as_ref()?;
+        let id = clic.highest()?;
+        let level = clic.interrupt_level(id) as u32;
+        (level > threshold).then_some((id, level))
+    }
+
+    // Takes interrupt `mcause` at the current pc. A selectively hardware
+    // vectored CLIC interrupt then jumps through its mtvt entry, with
+    // mcause.minhv set while the entry is read.
+    fn take_interrupt(&mut self, image: &mut [u8], mcause: u32) {
+        self.extraflags &= !4; // Interrupts wake the hart from WFI.
+        self.take_trap(mcause, 0);
+        let id = mcause & MCAUSE_EXCCODE;
+        let Some(clic) = self.clic.as_mut() else {
+            return;
+        };
+        if self.mtvec & MTVEC_MODE != MTVEC_MODE_CLIC || !clic.is_vectored(id) {
+            return;
+        }
+        clic.acknowledge(id);
+        let entry = self.mtvt.wrapping_add(4 * id);
+        self.mcause |= MCAUSE_MINHV;
+        match self.load(image, entry, 4) {
+            Ok(target) => {
+                self.mcause &= !MCAUSE_MINHV;
+                self.pc = target & !1;
+            }
+            Err(_) => {
+                // Reported at the table entry, the handler sees minhv and the
+                // interrupted context still in mstatus and mcause.mpil.
+                self.mcause = (self.mcause & (MCAUSE_MINHV | MCAUSE_MPIL))
+                    | TrapCause::InstructionAccessFault.mcause();
+                self.mepc = entry;
+                self.mtval = entry;
+            }
+        }
+    }
+
+    // SEIP as the PLIC drives it. The mip bit itself is only the part M-mode
+    // software writes, reads of mip and sip see both.
+    fn external_seip(&self) -> u32 {
+        if self.config.isa.supervisor && self.plic.interrupt_pending(PLIC_CONTEXT_S) {
+            MIP_SEIP
+        } else {
+            0
+        }
+    }
+
+    /// Maps a peripheral at `base..base + size`, see `MmioBus::register`.
+    /// Guest accesses outside RAM, the CLINT, the PLIC and any registered device raise
+    /// a load/store access fault.
+    ///
+    /// Panics as well if the range overlaps RAM, the CLINT, the PLIC or the
+    /// CLIC, which are decoded first and would hide the device.
+    pub fn register_mmio(&mut self, base: u32, size: u32, device: Box<dyn MmioDevice>) -> usize {
+        let config = &self.config;
+        let windows = [
+            ("RAM", config.ram_base, config.ram_size),
+            ("CLINT", config.clint_base, CLINT_SIZE),
+            ("PLIC", config.plic_base, PLIC_SIZE),
+        ];
+        let clic = config
+            .clic_base
+            .map(|clic_base| ("CLIC", clic_base, CLIC_SIZE));
+        for (name, start, len) in windows.into_iter().chain(clic) {
+            if (base as u64) < start as u64 + len as u64
+                && (start as u64) < base as u64 + size as u64
+            {
+                panic!(
+                    "MMIO range {:#010x}+{:#x} overlaps the {} at {:#010x}+{:#x}",
+                    base, size, name, start, len
+                );
+            }
+        }
+        self.mmio.register(base, size, device)
+    }
+
+    pub fn get_mmio_mut(&mut self) -> &mut MmioBus {
+        &mut self.mmio
+    }
+
+    /// Defines CSR `csrno` (0..0x1000) with host callbacks, replacing any
+    /// built-in CSR of that number. Without a `write` callback the CSR is
+    /// read-only and writing it raises illegal instruction, as does any
+    /// access to a CSR that is neither built in nor registered.
+    pub fn register_csr(&mut self, csrno: u32, read: CsrReadFn, write: Option<CsrWriteFn>) {
+        assert!(csrno < 0x1000, "CSR number {:#x} out of range", csrno);
+        self.custom_csrs.insert(csrno, CustomCsr { read, write });
+        self.misa |= MISA_X;
+    }
+
+    /// Executes every instruction of major opcode `opcode` with `handler`,
+    /// replacing any previous one.
+    pub fn register_custom_opcode(&mut self, opcode: CustomOpcode, handler: CustomInsnFn) {
+        self.custom_opcodes[opcode as usize] = Some(handler);
+        self.misa |= MISA_X;
+    }
+
+    // Refreshes the MSIP/MTIP/MEIP bits driven by the CLINT and the PLIC and
+    // returns the mcause of the highest priority interrupt that should be
+    // taken now, if any.
+    fn pending_interrupt(&mut self) -> Option<u32> {
+        let time = self.get_time();
+        self.mip &= !(MIP_MSIP | MIP_MTIP | MIP_MEIP);
+        if self.clint.software_pending() {
+            self.mip |= MIP_MSIP;
+        }
+        if self.clint.timer_pending(time) {
+            self.mip |= MIP_MTIP;
+        }
+        if self.plic.interrupt_pending(PLIC_CONTEXT_M) {
+            self.mip |= MIP_MEIP;
+        }
+        // The same sources are CLIC interrupts 3, 7 and 11.
+        let mip = self.mip;
+        if let Some(clic) = self.clic.as_mut() {
+            for id in [3, 7, 11] {
+                clic.set_level(id, mip & (1 << id) != 0);
+            }
+        }
+        if self.clic_mode() {
+            // M-mode handlers are preempted by higher levels only, and only
+            // while they keep MIE set.
+            let privilege = self.extraflags & 3;
+            if privilege == 3 && self.mstatus & MSTATUS_MIE == 0 {
+                return None;
+            }
+            let threshold = if privilege == 3 {
+                self.interrupt_level().max(self.mintthresh)
+            } else {
+                0
+            };
+            return self
+                .clic_interrupt(threshold)
+                .map(|(id, _)| MCAUSE_INTERRUPT | id);
+        }
+
+        // Interrupts for a mode are globally enabled by its xIE bit while in that
+        // mode, always in less privileged modes and never in more privileged ones.
+        let privilege = self.extraflags & 3;
+        let m_enabled = privilege < 3 || self.mstatus & MSTATUS_MIE != 0;
+        let s_enabled = privilege < 1 || (privilege == 1 && self.mstatus & MSTATUS_SIE != 0);
+        let pending = (self.mip | self.external_seip()) & self.mie;
+        let mut takeable = 0;
+        if m_enabled {
+            takeable |= pending & !self.mideleg;
+        }
+        if s_enabled {
+            takeable |= pending & self.mideleg;
+        }
+        // Priority order: MEI, MSI, MTI, SEI, SSI, STI.
+        [11, 3, 7, 9, 1, 5]
+            .into_iter()
+            .find(|cause| takeable & (1 << cause) != 0)
+            .map(|cause| 0x80000000 | cause)
+    }
+
+    // Whether an interrupt is pending that ends a WFI: any enabled one, even
+    // if mstatus masks it. Expects `pending_interrupt` to have updated mip.
+    fn wakeup_pending(&self) -> bool {
+        if self.clic_mode() {
+            let threshold = if self.extraflags & 3 == 3 {
+                self.interrupt_level().max(self.mintthresh)
+            } else {
+                0
+            };
+            self.clic_interrupt(threshold).is_some()
+        } else {
+            (self.mip | self.external_seip()) & self.mie != 0
+        }
+    }
+
+    /// Whether the hart is stopped in WFI.
+    pub fn is_waiting(&self) -> bool {
+        self.extraflags & 4 != 0
+    }
+
+    /// The time at which the CLINT timer fires next, if it is armed. A host
+    /// whose guest waits in WFI can sleep until then, or `set_time` to it.
+    pub fn get_next_deadline(&self) -> Option<u64> {
+        let mtimecmp = self.clint.get_mtimecmp();
+        (mtimecmp != u64::MAX).then_some(mtimecmp)
+    }
+
+    pub fn get_cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    pub fn get_instret(&self) -> u64 {
+        self.instret
+    }
+
+    // Counts one retired instruction, honouring mcountinhibit.
+    fn retire(&mut self) {
+        self.retired += 1;
+        self.ticks = self.ticks.wrapping_add(1);
+        if self.mcountinhibit & COUNTER_CY == 0 {
+            self.cycle = self.cycle.wrapping_add(1);
+        }
+        if self.mcountinhibit & COUNTER_IR == 0 {
+            self.instret = self.instret.wrapping_add(1);
+        }
+    }
+
+    // Cycles an instruction takes beyond the one counted when it retires.
+    fn stall(&mut self, cycles: u64) {
+        self.ticks = self.ticks.wrapping_add(cycles);
+        if self.mcountinhibit & COUNTER_CY == 0 {
+            self.cycle = self.cycle.wrapping_add(cycles);
+        }
+    }
+
+    // mepc/sepc as seen by the guest: bit 1 is masked while IALIGN is 32.
+    fn read_epc(&self, epc: u32) -> u32 {
+        if self.misa & MISA_C != 0 {
+            epc
+        } else {
+            epc & !3
+        }
+    }
+
+    // F instructions and CSRs are illegal without F or while mstatus.FS is Off.
+    fn fp_enabled(&self) -> bool {
+        self.misa & MISA_F != 0 && self.mstatus & MSTATUS_FS != 0
+    }
+
+    fn set_fs_dirty(&mut self) {
+        self.mstatus |= MSTATUS_FS | MSTATUS_SD;
+    }
+
+    // mstatus bits the guest can write through sstatus.
+    fn sstatus_writable(&self) -> u32 {
+        let sstatus = if self.config.isa.supervisor {
+            SSTATUS_MASK
+        } else {
+            0
+        };
+        if self.misa & MISA_F != 0 {
+            sstatus | MSTATUS_FS
+        } else {
+            sstatus
+        }
+    }
+
+    // The S-mode interrupt bits of mip and mie, which only exist with S-mode.
+    fn s_interrupts(&self) -> u32 {
+        if self.config.isa.supervisor {
+            MIP_S_ALL
+        } else {
+            0
+        }
+    }
+
+    // Recomputes mstatus.SD after a write to FS.
+    fn update_sd(&mut self) {
+        if self.mstatus & MSTATUS_FS == MSTATUS_FS {
+            self.mstatus |= MSTATUS_SD;
+        } else {
+            self.mstatus &= !MSTATUS_SD;
+        }
+    }
+
+    pub fn get_pmp(&self) -> &Pmp {
+        &self.pmp
+    }
+
+    // Whether PMP allows the access. Loads and stores from M-mode use the
+    // privilege in mstatus.MPP while mstatus.MPRV is set.
+    fn pmp_allows(&self, addr: u32, size: u32, access: AccessType) -> bool {
+        self.pmp
+            .allows(addr, size, access, self.access_privilege(access))
+    }
+
+    // The privilege an access is made with: loads and stores from M-mode use
+    // mstatus.MPP while mstatus.MPRV is set.
+    fn access_privilege(&self, access: AccessType) -> Privilege {
+        let privilege = self.get_privilege();
+        if access != AccessType::Fetch
+            && privilege == Privilege::Machine
+            && self.mstatus & MSTATUS_MPRV != 0
+        {
+            Privilege::from_bits(self.mstatus >> 11)
+        } else {
+            privilege
+        }
+    }
+
+    // RAM usable by `step`, never past the end of the image even if it is
+    // smaller than configured.
+    fn ram_size(&self, image: &[u8]) -> u32 {
+        self.config
+            .ram_size
+            .min(image.len().try_into().unwrap_or(u32::MAX))
+    }
+
+    // Offset into `image` of the `size` bytes at physical address `paddr`, if
+    // they all lie in RAM.
+    fn ram_offset(&self, image: &[u8], paddr: u64, size: u32) -> Option<u32> {
+        let ofs = paddr.checked_sub(self.config.ram_base as u64)?;
+        if ofs + size as u64 <= self.ram_size(image) as u64 {
+            Some(ofs as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Number of misaligned loads and stores emulated so far.
+    pub fn get_misaligned_count(&self) -> u64 {
+        self.misaligned_accesses
+    }
+
+    // Loads the `size`-byte value at virtual address `vaddr`, zero-extended.
+    // Errors carry the faulting address for mtval.
+    pub(crate) fn load(
+        &mut self,
+        image: &mut [u8],
+        vaddr: u32,
+        size: u32,
+    ) -> Result<u32, (TrapCause, u32)> {
+        if vaddr.is_multiple_of(size) {
+            return self.load_aligned(image, vaddr, size);
+        }
+        match self.config.misaligned {
+            MisalignedPolicy::Trap => Err((TrapCause::LoadAddressMisaligned, vaddr)),
+            MisalignedPolicy::Emulate => {
+                let bytes = self.misaligned_bytes(image, vaddr, size, AccessType::Load)?;
+                let mut val = 0;
+                for (i, &(_, ofs)) in bytes[..size as usize].iter().enumerate() {
+                    val |= (minirv32_load1(ofs, image) as u32) << (8 * i);
+                }
+                self.misaligned_accesses += 1;
+                Ok(val)
+            }
+        }
+    }
+
+    fn load_aligned(
+        &mut self,
+        image: &mut [u8],
+        vaddr: u32,
+        size: u32,
+    ) -> Result<u32, (TrapCause, u32)> {
+        let paddr = self
+            .translate(image, vaddr, size, AccessType::Load)
+            .map_err(|cause| (cause, vaddr))?;
+        let fault = (TrapCause::LoadAccessFault, vaddr);
+        if !self.pmp_allows(paddr, size, AccessType::Load) {
+            return Err(fault);
+        }
+        if let Some(ofs) = self.ram_offset(image, paddr as u64, size) {
+            return Ok(match size {
+                1 => minirv32_load1(ofs, image) as u32,
+                2 => minirv32_load2(ofs, image) as u32,
+                _ => minirv32_load4(ofs, image),
+            });
+        }
+        if self.clint.contains(paddr) {
+            // CLINT registers are word-sized, only LW is supported.
+            return match self.clint.load(paddr, self.get_time()) {
+                Some(val) if size == 4 => Ok(val),
+                _ => Err(fault),
+            };
+        }
+        if let Some(clic) = &self.clic
+            && clic.contains(paddr)
+        {
+            return clic.load(paddr, size).ok_or(fault);
+        }
+        if self.plic.contains(paddr) {
+            // PLIC registers are word-sized as well.
+            return match size {
+                4 => self.plic.load(paddr).ok_or(fault),
+                _ => Err(fault),
+            };
+        }
+        self.mmio.load(paddr, size).ok_or(fault)
+    }
+
+    // Stores the low `size` bytes of `val` at virtual address `vaddr`. An
+    // emulated misaligned store that faults writes none of its bytes.
+    pub(crate) fn store(
+        &mut self,
+        image: &mut [u8],
+        vaddr: u32,
+        size: u32,
+        val: u32,
+    ) -> Result<(), (TrapCause, u32)> {
+        if vaddr.is_multiple_of(size) {
+            return self.store_aligned(image, vaddr, size, val);
+        }
+        match self.config.misaligned {
+            MisalignedPolicy::Trap => Err((TrapCause::StoreAddressMisaligned, vaddr)),
+            MisalignedPolicy::Emulate => {
+                let bytes = self.misaligned_bytes(image, vaddr, size, AccessType::Store)?;
+                for (i, &(paddr, ofs)) in bytes[..size as usize].iter().enumerate() {
+                    self.drop_reservation(paddr, 1);
+                    minirv32_store1(ofs, (val >> (8 * i)) as u8, image);
+                }
+                self.misaligned_accesses += 1;
+                Ok(())
+            }
+        }
+    }
+
+    // Translates and checks every byte of an emulated misaligned access
+    // before any of it is performed, returning each byte's physical address
+    // and offset into `image`. Misaligned accesses outside RAM raise an
+    // access fault, as device registers can't be split into bytes.
+    fn misaligned_bytes(
+        &mut self,
+        image: &mut [u8],
+        vaddr: u32,
+        size: u32,
+        access: AccessType,
+    ) -> Result<[(u32, u32); 4], (TrapCause, u32)> {
+        let mut bytes = [(0, 0); 4];
+        for (i, byte) in bytes[..size as usize].iter_mut().enumerate() {
+            let vaddr = vaddr.wrapping_add(i as u32);
+            let paddr = self
+                .translate(image, vaddr, 1, access)
+                .map_err(|cause| (cause, vaddr))?;
+            let fault = (TrapCause::access_fault(access), vaddr);
+            if !self.pmp_allows(paddr, 1, access) {
+                return Err(fault);
+            }
+            let ofs = self.ram_offset(image, paddr as u64, 1).ok_or(fault)?;
+            *byte = (paddr, ofs);
+        }
+        Ok(bytes)
+    }
+
+    // A store to the reserved word makes the next SC.W fail.
+    fn drop_reservation(&mut self, paddr: u32, size: u32) {
+        if let Some(reserved) = self.reservation
+            && paddr < reserved.wrapping_add(4)
+            && reserved < paddr.wrapping_add(size)
+        {
+            self.reservation = None;
+        }
+    }
+
+    fn store_aligned(
+        &mut self,
+        image: &mut [u8],
+        vaddr: u32,
+        size: u32,
+        val: u32,
+    ) -> Result<(), (TrapCause, u32)> {
+        let paddr = self
+            .translate(image, vaddr, size, AccessType::Store)
+            .map_err(|cause| (cause, vaddr))?;
+        let fault = (TrapCause::StoreAccessFault, vaddr);
+        if !self.pmp_allows(paddr, size, AccessType::Store) {
+            return Err(fault);
+        }
+        if let Some(ofs) = self.ram_offset(image, paddr as u64, size) {
+            self.drop_reservation(paddr, size);
+            match size {
+                1 => minirv32_store1(ofs, val as u8, image),
+                2 => minirv32_store2(ofs, val as u16, image),
+                _ => minirv32_store4(ofs, val, image),
+            }
+            return Ok(());
+        }
+        if self.clint.contains(paddr) {
+            // CLINT registers are word-sized, only SW is supported.
+            let mut time = self.get_time();
+            if size == 4 && self.clint.store(paddr, val, &mut time) {
+                self.set_time(time);
+                return Ok(());
+            }
+            return Err(fault);
+        }
+        if let Some(clic) = &mut self.clic
+            && clic.contains(paddr)
+        {
+            return if clic.store(paddr, size, val) {
+                Ok(())
+            } else {
+                Err(fault)
+            };
+        }
+        if self.plic.contains(paddr) {
+            return if size == 4 && self.plic.store(paddr, val) {
+                Ok(())
+            } else {
+                Err(fault)
+            };
+        }
+        if self.mmio.store(paddr, size, val) {
+            Ok(())
+        } else {
+            Err(fault)
+        }
+    }
+
+    // Translates virtual address `vaddr` for a `size`-byte `access`, or
+    // returns the exception it raises (mtval is `vaddr`). Addresses are
+    // physical in M-mode and while satp is Bare.
+    fn translate(
+        &mut self,
+        image: &mut [u8],
+        vaddr: u32,
+        size: u32,
+        access: AccessType,
+    ) -> Result<u32, TrapCause> {
+        let privilege = self.access_privilege(access);
+        if self.satp & SATP_MODE_SV32 == 0 || privilege == Privilege::Machine {
+            return Ok(vaddr);
+        }
+        // Accesses spanning two pages are reported as misaligned.
+        if vaddr % PAGE_SIZE + size > PAGE_SIZE {
+            return Err(TrapCause::misaligned(access));
+        }
+        let vpn = vaddr / PAGE_SIZE;
+        let ppn = match self.tlb.lookup(vpn) {
+            // A store to a clean page walks again to set its D bit.
+            Some((ppn, flags)) if access != AccessType::Store || flags & PTE_D != 0 => {
+                if !mmu::leaf_allows(
+                    flags,
+                    access,
+                    privilege,
+                    self.mstatus & MSTATUS_SUM != 0,
+                    self.mstatus & MSTATUS_MXR != 0,
+                ) {
+                    return Err(TrapCause::page_fault(access));
+                }
+                ppn
+            }
+            _ => {
+                let (ppn, flags) = self.walk(image, vaddr, access, privilege)?;
+                self.tlb.insert(vpn, ppn, flags);
+                ppn
+            }
+        };
+        Ok(ppn * PAGE_SIZE + vaddr % PAGE_SIZE)
+    }
+
+    // Walks the Sv32 page table for `vaddr` and sets the A bit of the leaf
+    // PTE, and its D bit for stores. Returns the physical page number of the
+    // 4 KiB page holding `vaddr` and the leaf PTE flags.
+    fn walk(
+        &mut self,
+        image: &mut [u8],
+        vaddr: u32,
+        access: AccessType,
+        privilege: Privilege,
+    ) -> Result<(u32, u32), TrapCause> {
+        let mut table = (self.satp & SATP_PPN) as u64 * PAGE_SIZE as u64;
+        for level in [1, 0] {
+            let pte_addr = table + ((vaddr >> (12 + 10 * level)) & 0x3ff) as u64 * 4;
+            // Page tables must be in RAM, and are accessed with S-mode PMP permissions.
+            let pte_ofs = self
+                .ram_offset(image, pte_addr, 4)
+                .filter(|_| {
+                    self.pmp
+                        .allows(pte_addr as u32, 4, AccessType::Load, Privilege::Supervisor)
+                })
+                .ok_or(TrapCause::access_fault(access))?;
+            let pte = minirv32_load4(pte_ofs, image);
+            if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W {
+                return Err(TrapCause::page_fault(access));
+            }
+            if pte & (PTE_R | PTE_X) == 0 {
+                // Pointer to the next level table.
+                table = (pte >> 10) as u64 * PAGE_SIZE as u64;
+                continue;
+            }
+
+            let mut ppn = pte >> 10;
+            if level == 1 {
+                // Megapages must be 4 MiB aligned.
+                if ppn & 0x3ff != 0 {
+                    return Err(TrapCause::page_fault(access));
+                }
+                ppn |= (vaddr >> 12) & 0x3ff;
+            }
+            if !mmu::leaf_allows(
+                pte,
+                access,
+                privilege,
+                self.mstatus & MSTATUS_SUM != 0,
+                self.mstatus & MSTATUS_MXR != 0,
+            ) {
+                return Err(TrapCause::page_fault(access));
+            }
+            let dirty = if access == AccessType::Store {
+                PTE_D
+            } else {
+                0
+            };
+            let updated = pte | PTE_A | dirty;
+            if updated != pte {
+                if !self
+                    .pmp
+                    .allows(pte_addr as u32, 4, AccessType::Store, Privilege::Supervisor)
+                {
+                    return Err(TrapCause::access_fault(access));
+                }
+                minirv32_store4(pte_ofs, updated, image);
+            }
+            // Pages above the 32-bit physical address space don't exist.
+            if ppn >= 1 << 20 {
+                return Err(TrapCause::access_fault(access));
+            }
+            return Ok((ppn, updated & 0xff));
+        }
+        // The level 0 PTE is not a leaf either.
+        Err(TrapCause::page_fault(access))
+    }
+
+    // Applies a CSR write to mstatus. MPP keeps its value if the new one is
+    // the reserved privilege 2 or a mode the hart doesn't have. The fields of
+    // missing modes are read-only zero.
+    fn write_mstatus(&mut self, write: CsrWrite) {
+        let old = self.mstatus;
+        let mut new = write.apply(old);
+        let isa = self.config.isa;
+        let mpp_legal = match (new & MSTATUS_MPP) >> 11 {
+            0 => isa.user,
+            1 => isa.supervisor,
+            2 => false,
+            _ => true,
+        };
+        if !mpp_legal {
+            new = (new & !MSTATUS_MPP) | (old & MSTATUS_MPP);
+        }
+        let mut writable = self.sstatus_writable() | MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP;
+        if isa.user {
+            writable |= MSTATUS_MPRV | MSTATUS_TW;
+        }
+        if isa.supervisor {
+            writable |= MSTATUS_TVM | MSTATUS_TSR;
+        }
+        self.mstatus = (old & !writable) | (new & writable);
+        self.update_sd();
+    }
+
+    // mnxti: applies `write` to mstatus like CSRRS/CSRRC would, and returns
+    // the mtvt entry of the highest-ranked interrupt that isn't hardware
+    // vectored and is above mcause.mpil and mintthresh, or zero. When written,
+    // it also starts servicing that interrupt: mintstatus and mcause take
+    // its level and number and an edge-triggered one stops being pending.
+    fn access_mnxti(&mut self, write: Option<CsrWrite>) -> u32 {
+        if let Some(write) = write {
+            self.write_mstatus(write);
+        }
+        let mpil = (self.mcause & MCAUSE_MPIL) >> MCAUSE_MPIL_SHIFT;
+        let next = self
+            .clic_interrupt(mpil.max(self.mintthresh))
+            .filter(|&(id, _)| self.clic.as_ref().is_some_and(|clic| !clic.is_vectored(id)));
+        let Some((id, level)) = next else {
+            return 0;
+        };
+        if write.is_some() {
+            if let Some(clic) = self.clic.as_mut() {
+                clic.acknowledge(id);
+            }
+            self.set_interrupt_level(level);
+            self.mcause =
+                (self.mcause & !(MCAUSE_INTERRUPT | MCAUSE_EXCCODE)) | MCAUSE_INTERRUPT | id;
+        }
+        self.mtvt.wrapping_add(4 * id)
+    }
+
+    // Reads CSR `csrno` and applies `write` to it, returning the old value,
+    // or `None` when the access is an illegal instruction: the CSR doesn't
+    // exist or it is read-only and `write` is set. `read` is false for
+    // CSRRW(I) with rd = x0, which must not trigger host read callbacks.
+    fn csr_access(&mut self, csrno: u32, write: Option<CsrWrite>, read: bool) -> Option<u32> {
+        if write.is_some() && csrno >> 10 == 3 {
+            return None; // Writing a read-only CSR is illegal.
+        }
+        // Bits 9:8 of the CSR number are the lowest privilege that may access it.
+        let privilege = self.extraflags & 3;
+        if (csrno >> 8) & 3 > privilege {
+            return None;
+        }
+        if matches!(csrno, 0xC00..=0xC02 | 0xC80..=0xC82) && !self.config.isa.zicntr {
+            return None;
+        }
+        // Below M-mode the user counters must be enabled in mcounteren, and
+        // in U-mode in scounteren as well if there is an S-mode.
+        if matches!(csrno, 0xC00..=0xC1F | 0xC80..=0xC9F) {
+            let bit = 1 << (csrno & 0x1f);
+            if (privilege < 3 && self.mcounteren & bit == 0)
+                || (privilege == 0 && self.config.isa.supervisor && self.scounteren & bit == 0)
+            {
+                return None;
+            }
+        }
+        // With mstatus.TVM, S-mode may not touch satp.
+        if csrno == 0x180 && privilege == 1 && self.mstatus & MSTATUS_TVM != 0 {
+            return None;
+        }
+        // The CLIC CSRs only exist with a CLIC.
+        if matches!(csrno, 0x307 | 0x345 | 0x347 | 0xFB1) && self.clic.is_none() {
+            return None;
+        }
+        // fflags, frm and fcsr only exist while the FPU is enabled.
+        if matches!(csrno, 0x001..=0x003) && !self.fp_enabled() {
+            return None;
+        }
+        if let Some(csr) = self.custom_csrs.get_mut(&csrno) {
+            if write.is_some() && csr.write.is_none() {
+                return None;
+            }
+            let old = if read { (csr.read)() } else { 0 };
+            if let (Some(write), Some(write_fn)) = (write, &mut csr.write) {
+                write_fn(write.apply(old));
+            }
+            return Some(old);
+        }
+        // The S-mode CSRs and the delegation registers only exist with S-mode,
+        // mcounteren only with U-mode.
+        let isa = self.config.isa;
+        if (!isa.supervisor && ((csrno >> 8) & 3 == 1 || matches!(csrno, 0x302 | 0x303)))
+            || (!isa.user && csrno == 0x306)
+        {
+            return None;
+        }
+
+        let counting_cycles = self.mcountinhibit & COUNTER_CY == 0;
+        let counting_instret = self.mcountinhibit & COUNTER_IR == 0;
+        let mideleg = self.mideleg;
+        let s_interrupts = self.s_interrupts();
+        Some(match csrno {
+            // F: fcsr and its fflags and frm fields. Writes dirty the FPU state.
+            0x001..=0x003 => {
+                let (shift, mask) = match csrno {
+                    0x001 => (0, 0x1f),
+                    0x002 => (5, 0x7),
+                    _ => (0, 0xff),
+                };
+                let mut field = (self.fcsr >> shift) & mask;
+                let old = warl(&mut field, mask, write);
+                if write.is_some() {
+                    self.fcsr = (self.fcsr & !(mask << shift)) | (field << shift);
+                    self.set_fs_dirty();
+                }
+                old
+            }
+
+            // Zicntr: user-level read-only views of the counters.
+            0xC00 => self.cycle as u32,
+            0xC80 => (self.cycle >> 32) as u32,
+            0xC01 => self.get_time() as u32,
+            0xC81 => (self.get_time() >> 32) as u32,
+            0xC02 => self.instret as u32,
+            0xC82 => (self.instret >> 32) as u32,
+            // hpmcounter3..31(h): no events are implemented, they read as zero.
+            0xC03..=0xC1F | 0xC83..=0xC9F => 0,
+
+            // sstatus, sie and sip: the S-mode parts of mstatus, mie and mip. Only
+            // interrupts delegated to S-mode are visible, and SSIP is the only
+            // pending bit S-mode can change.
+            0x100 => {
+                let writable = self.sstatus_writable();
+                let old = warl(&mut self.mstatus, writable, write);
+                self.update_sd();
+                old & SSTATUS_READ_MASK
+            }
+            0x104 => warl(&mut self.mie, mideleg, write) & mideleg,
+            0x144 => {
+                let external = self.external_seip();
+                (warl(&mut self.mip, mideleg & MIP_SSIP, write) | external) & mideleg
+            }
+            // stvec: direct or vectored mode.
+            0x105 => {
+                let old = self.stvec;
+                if let Some(write) = write {
+                    self.stvec = legal_tvec(old, write.apply(old), false);
+                }
+                old
+            }
+            0x106 => warl(&mut self.scounteren, !0, write),
+            // senvcfg: none of the optional features exist.
+            0x10A => 0,
+            0x140 => warl(&mut self.sscratch, !0, write),
+            0x141 => {
+                let old = warl(&mut self.sepc, !1, write);
+                self.read_epc(old)
+            }
+            0x142 => warl(&mut self.scause, !0, write),
+            0x143 => warl(&mut self.stval, !0, write),
+            // satp: Bare or Sv32, without ASIDs.
+            0x180 => {
+                let old = warl(&mut self.satp, SATP_MODE_SV32 | SATP_PPN, write);
+                if write.is_some() {
+                    self.tlb.flush();
+                }
+                old
+            }
+
+            // mstatus: MPP=2 is reserved and leaves MPP unchanged.
+            0x300 => {
+                let old = self.mstatus;
+                if let Some(write) = write {
+                    self.write_mstatus(write);
+                }
+                old
+            }
+            // misa: only the configured extensions can be toggled. Dropping C is
+            // ignored when the next instruction isn't 4-byte aligned.
+            0x301 => {
+                let mut writable =
+                    self.config.isa.misa_writable & self.config.isa.misa() & MISA_TOGGLEABLE;
+                if self.pc.wrapping_add(4) & 2 != 0 {
+                    writable &= !MISA_C;
+                }
+                warl(&mut self.misa, writable, write)
+            }
+            0x302 => warl(&mut self.medeleg, MEDELEG_MASK, write),
+            // Only the S-mode interrupts can be delegated.
+            0x303 => warl(&mut self.mideleg, MIP_S_ALL, write),
+            // mie and mip read as zero in CLIC mode.
+            0x304 | 0x344 if self.clic_mode() => 0,
+            0x304 => warl(
+                &mut self.mie,
+                s_interrupts | MIP_MSIP | MIP_MTIP | MIP_MEIP,
+                write,
+            ),
+            // mtvec: direct or vectored mode, or CLIC mode when there is a CLIC.
+            0x305 => {
+                let old = self.mtvec;
+                if let Some(write) = write {
+                    self.mtvec = legal_tvec(old, write.apply(old), self.clic.is_some());
+                }
+                old
+            }
+            // CLIC: the vector table base, 64-byte aligned.
+            0x307 => warl(&mut self.mtvt, !0x3f, write),
+            0x306 => warl(&mut self.mcounteren, !0, write),
+            // menvcfg(h): none of the optional features exist.
+            0x30A | 0x31A => 0,
+            // mstatush: MBE/SBE are zero, memory is always little-endian.
+            0x310 => 0,
+            // mcountinhibit: time can't be inhibited.
+            0x320 => warl(&mut self.mcountinhibit, !COUNTER_TM, write),
+            // mhpmevent3..31: no events are implemented.
+            0x323..=0x33F => 0,
+            0x340 => warl(&mut self.mscratch, !0, write),
+            0x341 => {
+                let old = warl(&mut self.mepc, !1, write);
+                self.read_epc(old)
+            }
+            // mcause: in CLIC mode, MPP and MPIE are aliases of the mstatus fields.
+            0x342 if self.clic_mode() => {
+                let mstatus = self.mstatus;
+                let old = self.mcause
+                    | ((mstatus & MSTATUS_MPP) >> 11) << 28
+                    | ((mstatus & MSTATUS_MPIE) >> 7) << 27;
+                if let Some(write) = write {
+                    let new = write.apply(old);
+                    self.mcause =
+                        new & (MCAUSE_INTERRUPT | MCAUSE_MINHV | MCAUSE_MPIL | MCAUSE_EXCCODE);
+                    let aliased =
+                        ((new & MCAUSE_MPP) >> 28) << 11 | ((new & MCAUSE_MPIE) >> 27) << 7;
+                    self.write_mstatus(CsrWrite::Assign(
+                        (mstatus & !(MSTATUS_MPP | MSTATUS_MPIE)) | aliased,
+                    ));
+                }
+                old
+            }
+            0x342 => warl(&mut self.mcause, !0, write),
+            0x343 => warl(&mut self.mtval, !0, write),
+            0x345 => self.access_mnxti(write),
+            0x347 => warl(&mut self.mintthresh, 0xff, write),
+            0xFB1 => self.mintstatus,
+            // mip: MSIP/MTIP/MEIP are driven by the interrupt controllers, the
+            // S-mode bits are raised by M-mode software and SEIP by the PLIC too.
+            0x344 => {
+                let external = self.external_seip();
+                warl(&mut self.mip, s_interrupts, write) | external
+            }
+            0x3A0..=0x3AF => {
+                let index = (csrno - 0x3A0) as usize;
+                let old = self.pmp.read_cfg(index);
+                if let Some(write) = write {
+                    self.pmp.write_cfg(index, write.apply(old));
+                }
+                old
+            }
+            0x3B0..=0x3EF => {
+                let index = (csrno - 0x3B0) as usize;
+                let old = self.pmp.read_addr(index);
+                if let Some(write) = write {
+                    self.pmp.write_addr(index, write.apply(old));
+                }
+                old
+            }
+            0xB00 => counter_half(&mut self.cycle, false, write, counting_cycles),
+            0xB80 => counter_half(&mut self.cycle, true, write, counting_cycles),
+            0xB02 => counter_half(&mut self.instret, false, write, counting_instret),
+            0xB82 => counter_half(&mut self.instret, true, write, counting_instret),
+            // mhpmcounter3..31(h): hardwired to zero.
+            0xB03..=0xB1F | 0xB83..=0xB9F => 0,
+            // mvendorid (non-commercial), marchid, mimpid, mhartid and mconfigptr.
+            // The FreeRTOS port uses mhartid to find its mtimecmp.
+            0xF11..=0xF15 => 0,
+            _ => return None,
+        })
+    }
+
+    // Executes an F computational instruction, returning the result and
+    // whether it goes to an f register (otherwise to an x register), or `None`
+    // if the instruction is illegal. Exception flags accrue into fflags.
+    fn exec_fp(&mut self, ir: u32) -> Option<(u32, bool)> {
+        if !self.fp_enabled() {
+            return None;
+        }
+        let funct3 = (ir >> 12) & 7;
+        let reg1 = ((ir >> 15) & 0x1f) as usize;
+        let reg2 = (ir >> 20) & 0x1f;
+        let f1 = self.fregs[reg1];
+        let f2 = self.fregs[reg2 as usize];
+        // rm = 7 selects frm. Reserved modes are only illegal when used.
+        let rm = RoundingMode::from_bits(if funct3 == 7 { self.fcsr >> 5 } else { funct3 });
+        let mut flags = 0;
+
+        let result = match ir & 0x7f {
+            0x43 | 0x47 | 0x4b | 0x4f => {
+                // Only the S format (fmt = 0) exists without D.
+                if (ir >> 25) & 3 != 0 {
+                    return None;
+                }
+                let f3 = self.fregs[(ir >> 27) as usize];
+                // FMSUB negates the addend, FNMSUB the product, FNMADD both.
+                let (neg_prod, neg_addend) = match ir & 0x7f {
+                    0x43 => (0, 0),
+                    0x47 => (0, SIGN),
+                    0x4b => (SIGN, 0),
+                    _ => (SIGN, SIGN),
+                };
+                let val = softfloat::mul_add(f1 ^ neg_prod, f2, f3 ^ neg_addend, rm?, &mut flags);
+                (val, true)
+            }
+            _ => match (ir >> 25, funct3, reg2) {
+                (0x00, _, _) => (softfloat::add(f1, f2, rm?, &mut flags), true),
+                (0x04, _, _) => (softfloat::sub(f1, f2, rm?, &mut flags), true),
+                (0x08, _, _) => (softfloat::mul(f1, f2, rm?, &mut flags), true),
+                (0x0c, _, _) => (softfloat::div(f1, f2, rm?, &mut flags), true),
+                (0x2c, _, 0) => (softfloat::sqrt(f1, rm?, &mut flags), true),
+                // FSGNJ, FSGNJN, FSGNJX
+                (0x10, 0, _) => ((f1 & !SIGN) | (f2 & SIGN), true),
+                (0x10, 1, _) => ((f1 & !SIGN) | (!f2 & SIGN), true),
+                (0x10, 2, _) => (f1 ^ (f2 & SIGN), true),
+                (0x14, 0, _) => (softfloat::min(f1, f2, &mut flags), true),
+                (0x14, 1, _) => (softfloat::max(f1, f2, &mut flags), true),
+                // FLE, FLT, FEQ
+                (0x50, 0, _) => (softfloat::le(f1, f2, &mut flags) as u32, false),
+                (0x50, 1, _) => (softfloat::lt(f1, f2, &mut flags) as u32, false),
+                (0x50, 2, _) => (softfloat::eq(f1, f2, &mut flags) as u32, false),
+                // FCVT.W.S, FCVT.WU.S
+                (0x60, _, 0) => (softfloat::to_i32(f1, rm?, &mut flags), false),
+                (0x60, _, 1) => (softfloat::to_u32(f1, rm?, &mut flags), false),
+                // FCVT.S.W, FCVT.S.WU
+                (0x68, _, 0) => (softfloat::from_i32(self.regs[reg1], rm?, &mut flags), true),
+                (0x68, _, 1) => (softfloat::from_u32(self.regs[reg1], rm?, &mut flags), true),
+                // FMV.X.W, FCLASS.S, FMV.W.X
+                (0x70, 0, 0) => (f1, false),
+                (0x70, 1, 0) => (softfloat::classify(f1), false),
+                (0x78, 0, 0) => (self.regs[reg1], true),
+                _ => return None,
+            },
+        };
+        if flags != 0 {
+            self.fcsr |= flags;
+            self.set_fs_dirty();
+        }
+        Some(result)
+    }
+
+    /// Runs up to `budget` instructions, stopping early at the same events as
+    /// `step`. Interrupts are taken between instructions and cost nothing.
+    /// Without a time source (see `set_time_source`), the same state, inputs
+    /// and budget always give the same result.
+    pub fn run(&mut self, image: &mut [u8], budget: u64) -> RunResult {
+        let start = self.retired;
+        loop {
+            let executed = self.retired - start;
+            let remaining = budget - executed;
+            let reason = if remaining == 0 {
+                StopReason::BudgetExhausted
+            } else {
+                match self.step(image, 0, remaining.min(i32::MAX as u64) as i32) {
+                    StepResult::Retired(_) => continue,
+                    StepResult::Ecall { privilege } => StopReason::Ecall { privilege },
+                    StepResult::Breakpoint => StopReason::Breakpoint,
+                    StepResult::Wfi => StopReason::Wfi,
+                    StepResult::Halt => StopReason::Halt,
+                    StepResult::Fault { cause, mtval, pc } => {
+                        StopReason::Fault { cause, mtval, pc }
+                    }
+                }
+            };
+            let executed = self.retired - start;
+            return RunResult {
+                executed,
+                remaining: budget - executed,
+                reason,
+            };
+        }
+    }
+
+    pub fn step(&mut self, image: &mut [u8], _v_proc_address: u32, count: i32) -> StepResult {
+        let mut trap: Option<TrapCause>;
+        let mut rval: u32;
+        let mut pc: u32 = self.pc;
+        let mut retired: u32 = 0;
+        for _icount in 0..count {
+            trap = None;
+            rval = 0;
+
+            // Interrupts are taken between instructions, mepc is the next one to run.
+            if let Some(cause) = self.pending_interrupt() {
+                self.pc = pc;
+                self.take_interrupt(image, cause);
+                pc = self.pc;
+            }
+            // After WFI the hart sleeps until an enabled interrupt is pending.
+            // If it is masked, execution simply resumes after the WFI.
+            if self.is_waiting() {
+                if !self.wakeup_pending() {
+                    return StepResult::Wfi;
+                }
+                self.extraflags &= !4;
+            }
+
+            let (raw_ir, ilen) = match self.fetch(image, pc) {
+                Ok(fetched) => fetched,
+                Err((cause, mtval)) => {
+                    self.pc = pc;
+                    return self.exception(cause, mtval);
+                }
+            };
+            // Compressed instructions run as their 32-bit equivalent.
+            let ir = if ilen == 4 {
+                raw_ir
+            } else {
+                match crate::rv32c::expand_compressed(raw_ir as u16) {
+                    Some(expanded) => expanded,
+                    None => {
+                        self.pc = pc;
+                        return self.exception(TrapCause::IllegalInstruction, raw_ir);
+                    }
+                }
+            };
+            if self.config.isa.e && uses_upper_x_registers(ir) {
+                self.pc = pc;
+                return self.exception(TrapCause::IllegalInstruction, raw_ir);
+            }
+            let mut rdid: u32 = (ir >> 7) & 0x1f;
+            // Whether rdid names an f register.
+            let mut fp_write = false;
+
+            match ir & 0x7f {
+                0x37 => {
+                    // LUI (0b0110111)
+                    rval = ir & 0xfffff000;
+                }
+                0x17 => {
+                    // AUIPC (0b0010111)
+                    rval = pc.wrapping_add(ir & 0xfffff000);
+                }
+                0x6F => {
+                    // JAL (0b1101111)
+                    let mut reladdy: u32 = ((ir & 0x80000000) >> 11)
+                        | ((ir & 0x7fe00000) >> 20)
+                        | ((ir & 0x00100000) >> 9)
+                        | (ir & 0x000ff000);
+
+                    if (reladdy & 0x00100000) != 0 {
+                        reladdy |= 0xffe00000; // Sign extension.
+                    }
+                    let target = pc.wrapping_add(reladdy);
+                    if self.jump_misaligned(target) {
+                        trap = Some(TrapCause::InstructionAddressMisaligned);
+                        rval = target;
+                    } else {
+                        rval = pc.wrapping_add(ilen);
+                        pc = target.wrapping_sub(ilen);
+                    }
+                }
+
+                0x67 => {
+                    // JALR (0b1100111)
+
+                    let imm: u32 = ir >> 20;
+
+                    let mut ext = 0;
+                    if (imm & 0x800) != 0 {
+                        ext = 0xfffff000;
+                    }
+
+                    let imm_se: u32 = imm | ext;
+                    // #define REG( x ) state->regs[x]
+                    let reg_idx = (ir >> 15) & 0x1f;
+                    let reg_val = self.regs[reg_idx as usize];
+                    let target = (reg_val.wrapping_add(imm_se)) & !1;
+                    if self.jump_misaligned(target) {
+                        trap = Some(TrapCause::InstructionAddressMisaligned);
+                        rval = target;
+                    } else {
+                        rval = pc.wrapping_add(ilen);
+                        pc = target.wrapping_sub(ilen);
+                    }
+
+                    /*pc = ( (REG( (ir >> 15) & 0x1f ) + imm_se) & ~1) - 4;*/
+                }
+
+                0x63 => {
+                    // Branch (0b1100011)
+
+                    let mut immm4: u32 = ((ir & 0xf00) >> 7)
+                        | ((ir & 0x7e000000) >> 20)
+                        | ((ir & 0x80) << 4)
+                        | ((ir >> 31) << 12);
+                    if (immm4 & 0x1000) != 0 {
+                        immm4 |= 0xffffe000;
+                    }
+                    let reg_idx1 = (ir >> 15) & 0x1f;
+                    let reg_idx2 = (ir >> 20) & 0x1f;
+                    let rs1: i32 = self.regs[reg_idx1 as usize] as i32;
+                    let rs2: i32 = self.regs[reg_idx2 as usize] as i32;
+                    let target = pc.wrapping_add(immm4);
+
+                    rdid = 0;
+                    let taken = match (ir >> 12) & 0x7 {
+                        // BEQ, BNE, BLT, BGE, BLTU, BGEU
+                        0 => rs1 == rs2,
+                        1 => rs1 != rs2,
+                        4 => rs1 < rs2,
+                        5 => rs1 >= rs2,
+                        6 => (rs1 as u32) < (rs2 as u32),
+                        7 => (rs1 as u32) >= (rs2 as u32),
+                        _ => {
+                            trap = Some(TrapCause::IllegalInstruction);
+                            false
+                        }
+                    };
+                    if taken && self.jump_misaligned(target) {
+                        trap = Some(TrapCause::InstructionAddressMisaligned);
+                        rval = target;
+                    } else if taken {
+                        pc = target.wrapping_sub(ilen);
+                    }
+                }
+
+                0x03 | 0x07 => {
+                    // Load (0b0000011), FLW (0b0000111)
+                    let is_fp = ir & 0x7f == 0x07;
+                    fp_write = is_fp;
+                    let reg_idx1 = (ir >> 15) & 0x1f;
+                    let rs1: u32 = self.regs[reg_idx1 as usize];
+                    let imm: u32 = ir >> 20;
+                    let imm_se: u32 = if (imm & 0x800) != 0 {
+                        imm | 0xfffff000
+                    } else {
+                        imm
+                    };
+                    let vaddr: u32 = rs1.wrapping_add(imm_se);
+                    // LB/LBU = 1 byte, LH/LHU = 2, LW = 4.
+                    let funct3 = (ir >> 12) & 0x7;
+                    let legal = if is_fp {
+                        funct3 == 2 && self.fp_enabled()
+                    } else {
+                        funct3 != 3 && funct3 <= 5
+                    };
+                    if !legal {
+                        trap = Some(TrapCause::IllegalInstruction);
+                    } else {
+                        match self.load(image, vaddr, 1 << (funct3 & 3)) {
+                            // LB and LH sign-extend, LBU and LHU don't.
+                            Ok(val) => {
+                                rval = match funct3 {
+                                    0 => val as i8 as u32,
+                                    1 => val as i16 as u32,
+                                    _ => val,
                                 }
-                                _ => {
-                                    todo!("CSR not implemented: {:#x}", csrno);
+                            }
+                            Err((cause, addr)) => {
+                                trap = Some(cause);
+                                rval = addr;
+                            }
+                        }
+                    }
+                }
+
+                0x23 | 0x27 => {
+                    // Store 0b0100011, FSW 0b0100111
+                    let is_fp = ir & 0x7f == 0x27;
+                    let reg1 = (ir >> 15) & 0x1f;
+                    let reg2 = (ir >> 20) & 0x1f;
+
+                    let rs1: u32 = self.regs[reg1 as usize];
+                    let rs2: u32 = if is_fp {
+                        self.fregs[reg2 as usize]
+                    } else {
+                        self.regs[reg2 as usize]
+                    };
+                    let mut addy: u32 = ((ir >> 7) & 0x1f) | ((ir & 0xfe000000) >> 20);
+
+                    if addy & 0x800 != 0 {
+                        addy |= 0xfffff000;
+                    }
+                    addy = addy.wrapping_add(rs1);
+                    rdid = 0;
+
+                    // SB = 1 byte, SH = 2, SW = 4.
+                    let funct3 = (ir >> 12) & 0x7;
+                    let legal = if is_fp {
+                        funct3 == 2 && self.fp_enabled()
+                    } else {
+                        funct3 <= 2
+                    };
+                    if !legal {
+                        trap = Some(TrapCause::IllegalInstruction);
+                    } else if let Err((cause, addr)) = self.store(image, addy, 1 << funct3, rs2) {
+                        trap = Some(cause);
+                        rval = addr;
+                    }
+                }
+
+                0x13 | 0x33 => {
+                    // Op-immediate 0b0010011
+                    // Op           0b0110011
+                    let mut imm: u32 = ir >> 20;
+                    let mask = if imm & 0x800 != 0 { 0xfffff000 } else { 0 };
+                    imm |= mask;
+                    let reg = (ir >> 15) & 0x1f;
+                    let rs1 = self.regs[reg as usize];
+                    let reg2 = imm & 0x1f;
+
+                    let is_reg = (!!(ir & 0x20)) != 0;
+                    let rs2 = if is_reg {
+                        self.regs[reg2 as usize]
+                    } else {
+                        imm
+                    };
+
+                    // With M disabled, funct7 = 1 fails the base encoding check.
+                    if is_reg && (ir >> 25) == 1 && self.misa & MISA_M != 0 {
+                        match (ir >> 12) & 7 {
+                            //funct7 = 0b0000001 = RV32M
+                            0 => {
+                                rval = rs1.wrapping_mul(rs2);
+                                // MUL
+                            }
+                            1 => {
+                                rval = (((rs1 as i32 as i64) * (rs2 as i32 as i64)) >> 32) as u32;
+                                // MULH
+                            }
+                            2 => {
+                                rval = (((rs1 as i32 as i64) * (rs2 as i64)) >> 32) as u32;
+                                // MULHSU
+                            }
+                            3 => {
+                                rval = (((rs1 as u64) * (rs2 as u64)) >> 32) as u32;
+                                // MULHU
+                            }
+                            4 => {
+                                // Division by zero gives -1, INT_MIN / -1 overflows back to INT_MIN.
+                                rval = if rs2 == 0 {
+                                    0xffffffff
+                                } else {
+                                    ((rs1 as i32).wrapping_div(rs2 as i32)) as u32
+                                };
+                                // DIV
+                            }
+                            5 => {
+                                rval = rs1.checked_div(rs2).unwrap_or(0xffffffff);
+                                // DIVU
+                            }
+                            6 => {
+                                // Remainder by zero gives the dividend, INT_MIN % -1 gives 0.
+                                rval = if rs2 == 0 {
+                                    rs1
+                                } else {
+                                    ((rs1 as i32).wrapping_rem(rs2 as i32)) as u32
+                                };
+                                // REM
+                            }
+                            7 => {
+                                rval = rs1.checked_rem(rs2).unwrap_or(rs1);
+                                // REMU
+                            }
+                            _ => unreachable!(),
+                        }
+                    } else if let Some(val) = crate::bitmanip::execute(
+                        ir,
+                        rs1,
+                        rs2,
+                        self.config.isa.zba,
+                        self.config.isa.zbb,
+                        self.config.isa.zbs,
+                    ) {
+                        rval = val;
+                    } else if !base_alu_encoding(ir) {
+                        // Includes the bit-manipulation encodings of disabled extensions.
+                        trap = Some(TrapCause::IllegalInstruction);
+                    } else {
+                        match ir >> 12 & 7 {
+                            0 => {
+                                rval = if is_reg && (ir & 0x40000000) != 0 {
+                                    rs1.wrapping_sub(rs2)
+                                } else {
+                                    //ignore overflow
+                                    rs1.wrapping_add(rs2)
+                                };
+                                // ADD/SUB
+                            }
+                            1 => {
+                                rval = rs1.wrapping_shl(rs2 & 0x1f);
+                                // SLL
+                            }
+                            2 => {
+                                rval = if (rs1 as i32) < (rs2 as i32) { 1 } else { 0 };
+                                // SLT
+                            }
+                            3 => {
+                                rval = if rs1 < rs2 { 1 } else { 0 };
+                                // SLTU
+                            }
+                            4 => {
+                                rval = rs1 ^ rs2;
+                                // XOR
+                            }
+                            5 => {
+                                rval = if (ir & 0x40000000) != 0 {
+                                    ((rs1 as i32).wrapping_shr(rs2 & 0x1f)) as u32
+                                } else {
+                                    rs1.wrapping_shr(rs2 & 0x1f)
+                                };
+                                // SRL/SRA
+                            }
+                            6 => {
+                                rval = rs1 | rs2;
+                                // OR
+                            }
+                            7 => {
+                                rval = rs1 & rs2;
+                                // AND
+                            }
+                            _ => {
+                                trap = Some(TrapCause::IllegalInstruction); // Illegal instruction
+                            }
+                        }
+                    }
+                }
+
+                0x43 | 0x47 | 0x4b | 0x4f | 0x53 => {
+                    // F: FMADD/FMSUB/FNMSUB/FNMADD (0b10000xx11) and OP-FP (0b1010011)
+                    match self.exec_fp(ir) {
+                        Some((val, to_freg)) => {
+                            rval = val;
+                            fp_write = to_freg;
+                        }
+                        None => trap = Some(TrapCause::IllegalInstruction),
+                    }
+                }
+
+                0x0f => {
+                    // 0b0001111
+                    rdid = 0; // fencetype = (ir >> 12) & 0b111; We ignore fences in this impl.
+                }
+
+                0x73 => {
+                    // Zifencei+Zicsr  (0b1110011)
+                    let csrno = ir >> 20;
+                    let microop = (ir >> 12) & 0x7;
+                    if microop & 3 != 0 && !self.config.isa.zicsr {
+                        trap = Some(TrapCause::IllegalInstruction);
+                    } else if microop & 3 != 0 {
+                        // It's a Zicsr function, the I forms take a 5-bit immediate instead of rs1.
+                        let rs1imm: u32 = (ir >> 15) & 0x1f;
+                        let operand = if microop & 4 != 0 {
+                            rs1imm
+                        } else {
+                            self.regs[rs1imm as usize]
+                        };
+                        // CSRRS/CSRRC(I) with x0/zero never write, so read-only CSRs can be read.
+                        let write = match microop & 3 {
+                            1 => Some(CsrWrite::Assign(operand)),
+                            _ if rs1imm == 0 => None,
+                            2 => Some(CsrWrite::Set(operand)),
+                            _ => Some(CsrWrite::Clear(operand)),
+                        };
+                        // CSRRW(I) with rd = x0 must not cause read side effects.
+                        let read = microop & 3 != 1 || rdid != 0;
+                        // A misa write looks at the alignment of the next instruction.
+                        self.pc = pc;
+                        match self.csr_access(csrno, write, read) {
+                            Some(old) => rval = old,
+                            None => trap = Some(TrapCause::IllegalInstruction),
+                        }
+                    } else if microop == 0x0 {
+                        // "SYSTEM" 0b000
+                        rdid = 0;
+
+                        let privilege = self.get_privilege();
+                        match csrno {
+                            // SFENCE.VMA: rs1/rs2 may name a page and an ASID, the whole TLB
+                            // is flushed either way.
+                            _ if ir >> 25 == 0x09 && ir & 0xf80 == 0 => {
+                                if !self.config.isa.supervisor
+                                    || privilege == Privilege::User
+                                    || (privilege == Privilege::Supervisor
+                                        && self.mstatus & MSTATUS_TVM != 0)
+                                {
+                                    trap = Some(TrapCause::IllegalInstruction);
+                                } else {
+                                    self.tlb.flush();
                                 }
                             }
-                        } else if microop == 0x0 {
-                            // "SYSTEM" 0b000
-                            rdid = 0;
-
-                            if (csrno & 0xff) == 0x02 {
-                                // MRET
-                                //https://raw.githubusercontent.com/riscv/virtual-memory/main/specs/663-Svpbmt.pdf
-                                //Table 7.6. MRET then in mstatus/mstatush sets MPV=0, MPP=0, MIE=MPIE, and MPIE=1. La
-                                // Should also update mstatus to reflect correct mode.
-                                let startmstatus = self.mstatus;
-                                let startextraflags = self.extraflags;
-                                self.mstatus = ((startmstatus & 0x80) >> 4)
-                                    | ((startextraflags & 3) << 11)
-                                    | 0x80;
-                                self.extraflags =
-                                    (startextraflags & !3) | ((startmstatus >> 11) & 3);
-                                //SETCSR( mstatus , (( startmstatus & 0x80) >> 4) | ((startextraflags&3) << 11) | 0x80 );
-                                //SETCSR( extraflags, (startextraflags & ~3) | ((startmstatus >> 11) & 3) );
-                                pc = self.mepc - 4;
-                            } else {
-                                match csrno {
-                                    0 => {
-                                        trap = if self.extraflags & 3 != 0 {
-                                            11 + 1
-                                        } else {
-                                            8 + 1
-                                        }; // ECALL; 8 = "Environment call from U-mode"; 11 = "Environment call from M-mode"
-                                    }
+                            // rs1 and rd must be zero for every SYSTEM instruction below.
+                            _ if ir & 0x000f8f80 != 0 => {
+                                trap = Some(TrapCause::IllegalInstruction);
+                            }
+                            0 => {
+                                trap = Some(TrapCause::ecall_from(privilege)); // ECALL
+                            }
 
-                                    1 => {
-                                        trap = 3 + 1;
-                                        // EBREAK 3 = "Breakpoint"
-                                    }
+                            1 => {
+                                trap = Some(TrapCause::Breakpoint);
+                                // EBREAK 3 = "Breakpoint"
+                            }
 
-                                    0x105 => {
-                                        //WFI (Wait for interrupts)
-                                        self.mstatus |= 8; //Enable interrupts
-                                        self.extraflags |= 4; //Infor environment we want to go to sleep.
-                                        self.pc = self.pc.wrapping_add(4);
-                                        return 1;
+                            0x302 if privilege == Privilege::Machine => {
+                                // MRET: return to mstatus.MPP with MIE = MPIE and MPIE = 1. MPP
+                                // becomes U, or M on a hart without U-mode, and MPRV is
+                                // cleared when leaving M-mode.
+                                // In CLIC mode with minhv set, mepc is the vector table
+                                // entry that failed to load, the return goes through it.
+                                let target = if self.clic_mode() && self.mcause & MCAUSE_MINHV != 0
+                                {
+                                    self.load(image, self.mepc, 4).map(|entry| entry & !1)
+                                } else {
+                                    Ok(self.read_epc(self.mepc))
+                                };
+                                match target {
+                                    Ok(target) => {
+                                        if self.clic_mode() {
+                                            // Back to the interrupted level.
+                                            self.mcause &= !MCAUSE_MINHV;
+                                            self.set_interrupt_level(
+                                                (self.mcause & MCAUSE_MPIL) >> MCAUSE_MPIL_SHIFT,
+                                            );
+                                        }
+                                        let mpp = (self.mstatus & MSTATUS_MPP) >> 11;
+                                        let mut mstatus =
+                                            self.mstatus & !(MSTATUS_MIE | MSTATUS_MPP);
+                                        if !self.config.isa.user {
+                                            mstatus |= MSTATUS_MPP;
+                                        }
+                                        if mstatus & MSTATUS_MPIE != 0 {
+                                            mstatus |= MSTATUS_MIE;
+                                        }
+                                        mstatus |= MSTATUS_MPIE;
+                                        if mpp != 3 {
+                                            mstatus &= !MSTATUS_MPRV;
+                                        }
+                                        self.mstatus = mstatus;
+                                        self.extraflags = (self.extraflags & !3) | mpp;
+                                        pc = target.wrapping_sub(ilen);
                                     }
-
-                                    _ => {
-                                        trap = 2 + 1;
+                                    Err((_, addr)) => {
+                                        trap = Some(TrapCause::InstructionAccessFault);
+                                        rval = addr;
                                     }
                                 }
                             }
-                        } else if microop == 0x2 {
-                            // Zifencei
-                            rdid = 0;
-                            match csrno {
-                                0x300 => {
-                                    rval = self.mstatus;
-                                }
-                                _ => {
-                                    trap = 2 + 2; // Illegal instruction
+
+                            // SRET is also allowed in S-mode, unless mstatus.TSR traps it.
+                            0x102
+                                if self.config.isa.supervisor
+                                    && (privilege == Privilege::Machine
+                                        || (privilege == Privilege::Supervisor
+                                            && self.mstatus & MSTATUS_TSR == 0)) =>
+                            {
+                                // SRET: return to mstatus.SPP with SIE = SPIE and SPIE = 1. SPP
+                                // becomes U, and MPRV is cleared as SRET never returns to M-mode.
+                                let spp = (self.mstatus & MSTATUS_SPP) >> 8;
+                                let mut mstatus =
+                                    self.mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV);
+                                if mstatus & MSTATUS_SPIE != 0 {
+                                    mstatus |= MSTATUS_SIE;
                                 }
+                                mstatus |= MSTATUS_SPIE;
+                                self.mstatus = mstatus;
+                                self.extraflags = (self.extraflags & !3) | spp;
+                                pc = self.read_epc(self.sepc).wrapping_sub(ilen);
+                            }
+
+                            // With mstatus.TW set, only M-mode may wait for interrupts.
+                            // U-mode never may if there is an S-mode.
+                            0x105
+                                if privilege == Privilege::Machine
+                                    || (self.mstatus & MSTATUS_TW == 0
+                                        && (privilege == Privilege::Supervisor
+                                            || !self.config.isa.supervisor)) =>
+                            {
+                                //WFI (Wait for interrupts)
+                                self.extraflags |= 4; //Infor environment we want to go to sleep.
+                                self.retire();
+                                self.pc = pc.wrapping_add(ilen);
+                                return StepResult::Wfi;
+                            }
+
+                            // xRET from a less privileged mode and anything else.
+                            _ => {
+                                trap = Some(TrapCause::IllegalInstruction);
                             }
-                        } else {
-                            trap = 2 + 1;
                         }
+                    } else {
+                        trap = Some(TrapCause::IllegalInstruction);
                     }
+                }
 
-                    0x2f => {
-                        // RV32A (0b00101111)
-                        todo!("Atomic instructions not yet implemented");
-                    }
-                    _ => {
-                        trap = 2 + 2; // Illegal instruction
+                0x2f => {
+                    // RV32A (0b00101111)
+                    let reg1 = (ir >> 15) & 0x1f;
+                    let reg2 = (ir >> 20) & 0x1f;
+                    let addy: u32 = self.regs[reg1 as usize];
+                    let mut rs2: u32 = self.regs[reg2 as usize];
+                    let irmid = (ir >> 27) & 0x1f;
+                    // LR.W faults as a load, SC.W and the AMOs fault as a store.
+                    let is_lr = irmid == 2;
+                    let access = if is_lr {
+                        AccessType::Load
+                    } else {
+                        AccessType::Store
+                    };
+
+                    let translated = if self.misa & MISA_A == 0 || (ir >> 12) & 0x7 != 2 {
+                        // Only the .W width exists on RV32.
+                        Err(TrapCause::IllegalInstruction)
+                    } else if addy & 3 != 0 {
+                        Err(TrapCause::misaligned(access))
+                    } else {
+                        self.translate(image, addy, 4, access)
+                    };
+                    // We don't implement atomics on UART or CLNT, only in RAM.
+                    let ram_ofs = match translated {
+                        Ok(paddr) if self.pmp_allows(paddr, 4, access) => self
+                            .ram_offset(image, paddr as u64, 4)
+                            .map(|ofs| (paddr, ofs)),
+                        _ => None,
+                    };
+                    if let Err(cause) = translated {
+                        trap = Some(cause);
+                        rval = addy;
+                    } else if let Some((paddr, ofs)) = ram_ofs {
+                        rval = minirv32_load4(ofs, image);
+
+                        let mut dowrite = true;
+                        match irmid {
+                            2 => {
+                                // LR.W (0b00010)
+                                dowrite = false;
+                                self.reservation = Some(paddr);
+                            }
+                            3 => {
+                                // SC.W (0b00011)
+                                // Succeeds only if our reservation is still on this address.
+                                // Either way the reservation is consumed.
+                                let valid = self.reservation.take() == Some(paddr);
+                                rval = if valid { 0 } else { 1 };
+                                dowrite = valid;
+                            }
+                            1 => {}                            // AMOSWAP.W (0b00001)
+                            0 => rs2 = rs2.wrapping_add(rval), // AMOADD.W (0b00000)
+                            4 => rs2 ^= rval,                  // AMOXOR.W (0b00100)
+                            12 => rs2 &= rval,                 // AMOAND.W (0b01100)
+                            8 => rs2 |= rval,                  // AMOOR.W (0b01000)
+                            16 => rs2 = (rs2 as i32).min(rval as i32) as u32, // AMOMIN.W (0b10000)
+                            20 => rs2 = (rs2 as i32).max(rval as i32) as u32, // AMOMAX.W (0b10100)
+                            24 => rs2 = rs2.min(rval),         // AMOMINU.W (0b11000)
+                            28 => rs2 = rs2.max(rval),         // AMOMAXU.W (0b11100)
+                            _ => {
+                                trap = Some(TrapCause::IllegalInstruction); // Illegal instruction
+                                dowrite = false;
+                            }
+                        }
+                        if dowrite {
+                            self.drop_reservation(paddr, 4);
+                            minirv32_store4(ofs, rs2, image);
+                        }
+                    } else {
+                        // Load / Store/AMO access fault.
+                        trap = Some(TrapCause::access_fault(access));
+                        rval = addy;
                     }
                 }
-
-                // If there was a trap, do NOT allow register writeback.
-                if trap != 0 {
-                    self.pc = pc;
-                    //MINIRV32_POSTEXEC( pc, ir, trap );
-                    break;
+                0x0b | 0x2b | 0x5b | 0x7b => {
+                    // custom-0..3: host handlers, illegal without one.
+                    let opcode = CustomOpcode::from_instruction(ir).unwrap();
+                    let rs1 = (ir >> 15) & 0x1f;
+                    let rs2 = (ir >> 20) & 0x1f;
+                    let insn = CustomInstruction {
+                        opcode,
+                        ir,
+                        rd: rdid,
+                        rs1,
+                        rs2,
+                        funct3: (ir >> 12) & 7,
+                        funct7: ir >> 25,
+                        rs1_val: self.regs[rs1 as usize],
+                        rs2_val: self.regs[rs2 as usize],
+                    };
+                    // The handler is taken out while it runs, so that it can
+                    // reach the rest of the hart through the context.
+                    let outcome = match self.custom_opcodes[opcode as usize].take() {
+                        Some(mut handler) => {
+                            let mut ctx = CustomContext { hart: self, image };
+                            let outcome = handler(&insn, &mut ctx);
+                            self.custom_opcodes[opcode as usize] = Some(handler);
+                            Some(outcome)
+                        }
+                        None => None,
+                    };
+                    match outcome {
+                        Some(CustomOutcome::Retire {
+                            value,
+                            extra_cycles,
+                        }) => {
+                            match value {
+                                Some(val) => rval = val,
+                                None => rdid = 0,
+                            }
+                            self.stall(extra_cycles);
+                        }
+                        Some(CustomOutcome::Trap { cause, mtval }) => {
+                            self.pc = pc;
+                            return self.exception(cause, mtval);
+                        }
+                        None => trap = Some(TrapCause::IllegalInstruction),
+                    }
                 }
-                if rdid != 0 {
-                    self.regs[rdid as usize] = rval; // Write back register.
+                _ => {
+                    trap = Some(TrapCause::IllegalInstruction); // Illegal instruction
                 }
             }
 
+            // If there was a trap, do NOT allow register writeback.
+            if let Some(cause) = trap {
+                self.pc = pc;
+                //MINIRV32_POSTEXEC( pc, ir, trap );
+                // Illegal instruction reports the (unexpanded) instruction bits.
+                let mtval = match cause {
+                    TrapCause::IllegalInstruction => raw_ir,
+                    _ if cause.has_address() => rval,
+                    _ => 0,
+                };
+                return self.exception(cause, mtval);
+            }
+            if fp_write {
+                self.fregs[rdid as usize] = rval;
+                self.set_fs_dirty();
+            } else if rdid != 0 {
+                self.regs[rdid as usize] = rval; // Write back register.
+            }
+
             //MINIRV32_POSTEXEC( pc, ir, trap );
 
-            pc = pc.wrapping_add(4);
+            self.retire();
+            pc = pc.wrapping_add(ilen);
+            retired += 1;
+        }
+
+        self.pc = pc;
+        StepResult::Retired(retired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MUL: u32 = 0;
+    const MULH: u32 = 1;
+    const MULHSU: u32 = 2;
+    const MULHU: u32 = 3;
+    const DIV: u32 = 4;
+    const DIVU: u32 = 5;
+    const REM: u32 = 6;
+    const REMU: u32 = 7;
+
+    const INT_MIN: u32 = 0x80000000;
+    const INT_MAX: u32 = 0x7fffffff;
+    const NEG1: u32 = 0xffffffff;
+
+    // Runs a single RV32M instruction `x3 = x1 <op> x2` and returns x3.
+    fn exec_m(funct3: u32, rs1: u32, rs2: u32) -> u32 {
+        exec_alu(
+            (1 << 25) | (2 << 20) | (1 << 15) | (funct3 << 12) | (3 << 7) | 0x33,
+            rs1,
+            rs2,
+        )
+    }
+
+    // Runs `ir` with x1 = `rs1` and x2 = `rs2` and returns x3.
+    fn exec_alu(ir: u32, rs1: u32, rs2: u32) -> u32 {
+        let mut image = vec![0u8; 16];
+        minirv32_store4(0, ir, &mut image);
+
+        let mut cpu = MiniRV32IMAState::new(None);
+        cpu.regs[1] = rs1;
+        cpu.regs[2] = rs2;
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.pc, MINIRV32_RAM_IMAGE_OFFSET + 4, "unexpected trap");
+        cpu.regs[3]
+    }
+
+    #[test]
+    fn mul() {
+        assert_eq!(exec_m(MUL, 7, 6), 42);
+        assert_eq!(exec_m(MUL, NEG1, 5), (-5i32) as u32);
+        assert_eq!(exec_m(MUL, INT_MIN, NEG1), INT_MIN);
+        assert_eq!(exec_m(MUL, 0x12345678, 0x9abcdef0), 0x242d2080);
+        assert_eq!(exec_m(MUL, INT_MAX, INT_MAX), 1);
+    }
+
+    #[test]
+    fn mulh() {
+        assert_eq!(exec_m(MULH, 7, 6), 0);
+        assert_eq!(exec_m(MULH, NEG1, NEG1), 0);
+        assert_eq!(exec_m(MULH, NEG1, 1), NEG1);
+        assert_eq!(exec_m(MULH, INT_MIN, INT_MIN), 0x40000000);
+        assert_eq!(exec_m(MULH, INT_MIN, INT_MAX), 0xc0000000);
+        assert_eq!(exec_m(MULH, INT_MAX, INT_MAX), 0x3fffffff);
+    }
+
+    #[test]
+    fn mulhsu() {
+        assert_eq!(exec_m(MULHSU, NEG1, NEG1), NEG1);
+        assert_eq!(exec_m(MULHSU, 1, NEG1), 0);
+        assert_eq!(exec_m(MULHSU, INT_MIN, NEG1), 0x80000000);
+        assert_eq!(exec_m(MULHSU, INT_MAX, NEG1), 0x7ffffffe);
+        assert_eq!(exec_m(MULHSU, 0, NEG1), 0);
+    }
+
+    #[test]
+    fn mulhu() {
+        assert_eq!(exec_m(MULHU, NEG1, NEG1), 0xfffffffe);
+        assert_eq!(exec_m(MULHU, INT_MIN, 2), 1);
+        assert_eq!(exec_m(MULHU, 0x12345678, 0x9abcdef0), 0x0b00ea4e);
+        assert_eq!(exec_m(MULHU, 0xffff, 0x10000), 0);
+    }
+
+    #[test]
+    fn div() {
+        assert_eq!(exec_m(DIV, 20, 6), 3);
+        assert_eq!(exec_m(DIV, (-20i32) as u32, 6), (-3i32) as u32);
+        assert_eq!(exec_m(DIV, 20, (-6i32) as u32), (-3i32) as u32);
+        assert_eq!(exec_m(DIV, 5, 0), NEG1);
+        assert_eq!(exec_m(DIV, INT_MIN, 0), NEG1);
+        assert_eq!(exec_m(DIV, INT_MIN, NEG1), INT_MIN);
+    }
+
+    #[test]
+    fn divu() {
+        assert_eq!(exec_m(DIVU, 20, 6), 3);
+        assert_eq!(exec_m(DIVU, NEG1, 2), INT_MAX);
+        assert_eq!(exec_m(DIVU, INT_MIN, NEG1), 0);
+        assert_eq!(exec_m(DIVU, 5, 0), NEG1);
+        assert_eq!(exec_m(DIVU, 0, 0), NEG1);
+    }
+
+    #[test]
+    fn rem() {
+        assert_eq!(exec_m(REM, 20, 6), 2);
+        assert_eq!(exec_m(REM, (-20i32) as u32, 6), (-2i32) as u32);
+        assert_eq!(exec_m(REM, 20, (-6i32) as u32), 2);
+        assert_eq!(exec_m(REM, 5, 0), 5);
+        assert_eq!(exec_m(REM, INT_MIN, 0), INT_MIN);
+        assert_eq!(exec_m(REM, INT_MIN, NEG1), 0);
+    }
+
+    #[test]
+    fn remu() {
+        assert_eq!(exec_m(REMU, 20, 6), 2);
+        assert_eq!(exec_m(REMU, NEG1, 10), 5);
+        assert_eq!(exec_m(REMU, INT_MIN, NEG1), INT_MIN);
+        assert_eq!(exec_m(REMU, 5, 0), 5);
+        assert_eq!(exec_m(REMU, 0, 0), 0);
+    }
+
+    #[test]
+    fn slt() {
+        // slt x3, x1, x2
+        let slt = |rs1, rs2| exec_alu(0x0020a1b3, rs1, rs2);
+        assert_eq!(slt(NEG1, 1), 1);
+        assert_eq!(slt(1, NEG1), 0);
+        assert_eq!(slt(5, 5), 0);
+        assert_eq!(slt(INT_MIN, 0), 1);
+        assert_eq!(slt(8, 1), 0);
+        // slti x3, x1, imm
+        let slti = |rs1, imm: u32| exec_alu(imm << 20 | 0x0000a193, rs1, 0);
+        assert_eq!(slti(-5i32 as u32, 0xffc), 1);
+        assert_eq!(slti(3, 0xfff), 0);
+        assert_eq!(slti(0x10, 2), 0);
+        assert_eq!(slti(1, 2), 1);
+    }
+
+    const AMOADD: u32 = 0b00000;
+    const AMOSWAP: u32 = 0b00001;
+    const LR: u32 = 0b00010;
+    const SC: u32 = 0b00011;
+    const AMOXOR: u32 = 0b00100;
+    const AMOOR: u32 = 0b01000;
+    const AMOAND: u32 = 0b01100;
+    const AMOMIN: u32 = 0b10000;
+    const AMOMAX: u32 = 0b10100;
+    const AMOMINU: u32 = 0b11000;
+    const AMOMAXU: u32 = 0b11100;
+
+    // Address of the word the RV32A tests operate on.
+    const DATA: u32 = MINIRV32_RAM_IMAGE_OFFSET + 0x100;
+
+    // `<funct5>.w rd, rs2, (rs1)`.
+    fn amo(funct5: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+        (funct5 << 27) | (rs2 << 20) | (rs1 << 15) | (2 << 12) | (rd << 7) | 0x2f
+    }
+
+    // `sw rs2, 0(rs1)`.
+    fn sw(rs2: u32, rs1: u32) -> u32 {
+        (rs2 << 20) | (rs1 << 15) | (2 << 12) | 0x23
+    }
+
+    // Loads `program` at the start of RAM, with x1 pointing at DATA.
+    fn boot(program: &[u32]) -> (MiniRV32IMAState, Vec<u8>) {
+        boot_with(MachineConfig::default(), program)
+    }
+
+    fn boot_with(config: MachineConfig, program: &[u32]) -> (MiniRV32IMAState, Vec<u8>) {
+        let mut image = vec![0u8; 0x200];
+        for (i, &ir) in program.iter().enumerate() {
+            minirv32_store4(i as u32 * 4, ir, &mut image);
+        }
+        let mut cpu = MiniRV32IMAState::with_config(config, None);
+        cpu.regs[1] = DATA;
+        (cpu, image)
+    }
+
+    fn data(image: &[u8]) -> u32 {
+        minirv32_load4(DATA - MINIRV32_RAM_IMAGE_OFFSET, image)
+    }
+
+    // Runs a single AMO on a word holding `old` with x2 = `operand`, and
+    // returns the value read into x3 and the word left in memory.
+    fn exec_amo(funct5: u32, old: u32, operand: u32) -> (u32, u32) {
+        let (mut cpu, mut image) = boot(&[amo(funct5, 3, 1, 2)]);
+        minirv32_store4(DATA - MINIRV32_RAM_IMAGE_OFFSET, old, &mut image);
+        cpu.regs[2] = operand;
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.pc, MINIRV32_RAM_IMAGE_OFFSET + 4, "unexpected trap");
+        (cpu.regs[3], data(&image))
+    }
+
+    #[test]
+    fn amo_ops() {
+        assert_eq!(exec_amo(AMOSWAP, 1, 2), (1, 2));
+        assert_eq!(exec_amo(AMOADD, NEG1, 2), (NEG1, 1));
+        assert_eq!(exec_amo(AMOXOR, 0b1100, 0b1010), (0b1100, 0b0110));
+        assert_eq!(exec_amo(AMOAND, 0b1100, 0b1010), (0b1100, 0b1000));
+        assert_eq!(exec_amo(AMOOR, 0b1100, 0b1010), (0b1100, 0b1110));
+        assert_eq!(exec_amo(AMOMIN, NEG1, 1), (NEG1, NEG1));
+        assert_eq!(exec_amo(AMOMAX, NEG1, 1), (NEG1, 1));
+        assert_eq!(exec_amo(AMOMINU, NEG1, 1), (NEG1, 1));
+        assert_eq!(exec_amo(AMOMAXU, NEG1, 1), (NEG1, NEG1));
+    }
+
+    #[test]
+    fn amo_misaligned_traps() {
+        let (mut cpu, mut image) = boot(&[amo(AMOADD, 3, 1, 2)]);
+        cpu.regs[1] = DATA + 2;
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.mcause, TrapCause::StoreAddressMisaligned as u32);
+        assert_eq!(cpu.mtval, DATA + 2);
+    }
+
+    #[test]
+    fn lr_sc_succeeds() {
+        let (mut cpu, mut image) = boot(&[amo(LR, 3, 1, 0), amo(SC, 4, 1, 2)]);
+        minirv32_store4(DATA - MINIRV32_RAM_IMAGE_OFFSET, 5, &mut image);
+        cpu.regs[2] = 7;
+        cpu.step(&mut image, 0, 2);
+        assert_eq!(cpu.regs[3], 5);
+        assert_eq!(cpu.regs[4], 0);
+        assert_eq!(data(&image), 7);
+    }
+
+    #[test]
+    fn sc_without_reservation_fails() {
+        let (mut cpu, mut image) = boot(&[amo(LR, 3, 1, 0), amo(SC, 4, 1, 2), amo(SC, 5, 1, 2)]);
+        cpu.regs[2] = 7;
+        cpu.step(&mut image, 0, 3);
+        // The first SC consumes the reservation.
+        assert_eq!(cpu.regs[4], 0);
+        assert_eq!(cpu.regs[5], 1);
+    }
+
+    #[test]
+    fn sc_fails_after_store() {
+        let program = [amo(LR, 3, 1, 0), sw(6, 1), amo(SC, 4, 1, 2)];
+        let (mut cpu, mut image) = boot(&program);
+        cpu.regs[2] = 7;
+        cpu.regs[6] = 9;
+        cpu.step(&mut image, 0, 3);
+        assert_eq!(cpu.regs[4], 1);
+        assert_eq!(data(&image), 9);
+    }
+
+    #[test]
+    fn sc_fails_after_trap() {
+        // The illegal instruction traps to the SC.
+        let program = [amo(LR, 3, 1, 0), 0, amo(SC, 4, 1, 2)];
+        let (mut cpu, mut image) = boot(&program);
+        cpu.mtvec = MINIRV32_RAM_IMAGE_OFFSET + 8;
+        cpu.regs[2] = 7;
+        let result = cpu.step(&mut image, 0, 2);
+        assert!(matches!(result, StepResult::Fault { .. }));
+        assert_eq!(cpu.pc, MINIRV32_RAM_IMAGE_OFFSET + 8);
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.regs[4], 1);
+        assert_eq!(data(&image), 0);
+    }
+
+    #[test]
+    fn sc_to_other_address_fails() {
+        let (mut cpu, mut image) = boot(&[amo(LR, 3, 1, 0), amo(SC, 4, 2, 6)]);
+        cpu.regs[2] = DATA + 4;
+        cpu.regs[6] = 7;
+        cpu.step(&mut image, 0, 2);
+        assert_eq!(cpu.regs[4], 1);
+        assert_eq!(
+            minirv32_load4(DATA + 4 - MINIRV32_RAM_IMAGE_OFFSET, &image),
+            0
+        );
+    }
+
+    const BASE: u32 = MINIRV32_RAM_IMAGE_OFFSET;
+
+    // Runs the first instruction of `program` with C enabled or not.
+    fn step_one(program: &[u32], compressed: bool) -> (MiniRV32IMAState, StepResult) {
+        let (mut cpu, mut image) = boot(program);
+        cpu.set_compressed(compressed);
+        cpu.regs[5] = BASE;
+        let result = cpu.step(&mut image, 0, 1);
+        (cpu, result)
+    }
+
+    fn assert_misaligned_jump(result: StepResult, target: u32) {
+        match result {
+            StepResult::Fault { cause, mtval, pc } => {
+                assert_eq!(cause, TrapCause::InstructionAddressMisaligned);
+                assert_eq!(mtval, target);
+                assert_eq!(pc, BASE);
+            }
+            _ => panic!("expected a misaligned target trap"),
         }
+    }
 
-        if trap != 0 {
-            // callback to notify trap
-            if let Some(callback) = self.callback_on_trap {
-                callback(trap);
+    #[test]
+    fn compressed_parcel_without_c_is_illegal() {
+        // c.li a0, 0 in the low half of the word.
+        let (cpu, result) = step_one(&[0x12344501], false);
+        assert!(matches!(
+            result,
+            StepResult::Fault {
+                cause: TrapCause::IllegalInstruction,
+                mtval: 0x12344501,
+                ..
             }
+        ));
+        assert_eq!(cpu.mtval, 0x12344501);
 
-            //return trap.try_into().unwrap();
-            if trap & 0x80000000 != 0 {
-                self.mcause = trap;
-                self.mtval = 0;
-                pc += 4; // PC needs to point to where the PC will return to.
-            } else {
-                self.mcause = trap - 1;
-                self.mtval = if trap > 5 && trap <= 8 { rval } else { pc };
+        let (cpu, _) = step_one(&[0x12344501], true);
+        assert_eq!(cpu.pc, BASE + 2);
+        assert_eq!(cpu.regs[10], 0);
+    }
+
+    #[test]
+    fn misaligned_jump_traps_at_the_jump() {
+        // jal ra, 6
+        let (cpu, result) = step_one(&[0x006000ef], false);
+        assert_misaligned_jump(result, BASE + 6);
+        assert_eq!(cpu.mepc, BASE);
+        assert_eq!(cpu.regs[1], DATA, "rd must not be written");
+        // jalr ra, 2(t0)
+        let (cpu, result) = step_one(&[0x002280e7], false);
+        assert_misaligned_jump(result, BASE + 2);
+        assert_eq!(cpu.regs[1], DATA, "rd must not be written");
+        // With C both are fine.
+        let (cpu, _) = step_one(&[0x006000ef], true);
+        assert_eq!(cpu.pc, BASE + 6);
+        assert_eq!(cpu.regs[1], BASE + 4);
+    }
+
+    #[test]
+    fn misaligned_branch_traps_only_when_taken() {
+        // beq zero, zero, 6
+        let (_, result) = step_one(&[0x00000363], false);
+        assert_misaligned_jump(result, BASE + 6);
+        // bne zero, zero, 6
+        let (cpu, result) = step_one(&[0x00001363], false);
+        assert!(matches!(result, StepResult::Retired(1)));
+        assert_eq!(cpu.pc, BASE + 4);
+    }
+
+    #[test]
+    fn clone_runs_independently() {
+        // csrr a0, 0x800 (a custom CSR) and sb a0, 0(t0) to a UART.
+        let (mut cpu, mut image) = boot(&[0x80002573, 0x00a28023]);
+        let written = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let sink = written.clone();
+        let uart = crate::uart::Uart8250::new(Box::new(move |b| sink.borrow_mut().push(b)), None);
+        cpu.register_mmio(
+            crate::uart::UART_BASE,
+            crate::uart::UART_SIZE,
+            Box::new(uart),
+        );
+        cpu.register_csr(0x800, Box::new(|| 0x41), None);
+        cpu.regs[5] = crate::uart::UART_BASE;
+
+        let mut copy = cpu.clone();
+        let mut copy_image = image.clone();
+        copy.step(&mut copy_image, 0, 2);
+        assert_eq!(copy.regs[10], 0x41);
+        assert_eq!(copy.pc, BASE + 8);
+        assert_eq!(cpu.regs[10], 0);
+        assert_eq!(cpu.pc, BASE);
+
+        cpu.step(&mut image, 0, 2);
+        assert_eq!(*written.borrow(), [0x41, 0x41]);
+    }
+
+    #[derive(Clone)]
+    struct NullDevice;
+
+    impl MmioDevice for NullDevice {}
+
+    #[test]
+    #[should_panic(expected = "overlaps the RAM")]
+    fn mmio_over_ram_panics() {
+        let mut cpu = MiniRV32IMAState::new(None);
+        cpu.register_mmio(BASE - 0x100, 0x200, Box::new(NullDevice));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps the CLINT")]
+    fn mmio_over_clint_panics() {
+        let mut cpu = MiniRV32IMAState::new(None);
+        cpu.register_mmio(CLINT_BASE + CLINT_SIZE - 4, 8, Box::new(NullDevice));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps the CLIC")]
+    fn mmio_over_clic_panics() {
+        let config = MachineConfig {
+            clic_base: Some(crate::clic::CLIC_BASE),
+            ..MachineConfig::default()
+        };
+        let mut cpu = MiniRV32IMAState::with_config(config, None);
+        cpu.register_mmio(crate::clic::CLIC_BASE, 4, Box::new(NullDevice));
+    }
+
+    // Runs WFI in `privilege` with mstatus.TW = `tw`, on a hart with or
+    // without S-mode, and reports whether the hart went to sleep as opposed
+    // to raising illegal instruction.
+    fn wfi_sleeps(privilege: Privilege, tw: bool, supervisor: bool) -> bool {
+        let mut config = MachineConfig::default();
+        config.isa.supervisor = supervisor;
+        let (mut cpu, mut image) = boot_with(config, &[0x10500073]);
+        // A NAPOT entry covering everything lets S- and U-mode fetch.
+        cpu.pmp.write_addr(0, u32::MAX);
+        cpu.pmp.write_cfg(0, 0x1f);
+        if tw {
+            cpu.mstatus |= MSTATUS_TW;
+        }
+        cpu.extraflags = (cpu.extraflags & !3) | privilege as u32;
+        match cpu.step(&mut image, 0, 1) {
+            StepResult::Wfi => true,
+            StepResult::Fault { cause, mtval, .. } => {
+                assert_eq!(cause, TrapCause::IllegalInstruction);
+                assert_eq!(mtval, 0x10500073);
+                false
             }
+            _ => panic!("WFI neither slept nor trapped"),
+        }
+    }
+
+    #[test]
+    fn wfi_privilege() {
+        for tw in [false, true] {
+            assert!(wfi_sleeps(Privilege::Machine, tw, true));
+            assert!(!wfi_sleeps(Privilege::User, tw, true));
+        }
+        assert!(wfi_sleeps(Privilege::Supervisor, false, true));
+        assert!(!wfi_sleeps(Privilege::Supervisor, true, true));
+        // Without S-mode, U-mode may wait unless TW is set.
+        assert!(wfi_sleeps(Privilege::User, false, false));
+        assert!(!wfi_sleeps(Privilege::User, true, false));
+    }
+
+    // Sv32 tests: the root table is at ROOT and a level 0 table at LEAVES.
+    const ROOT: u32 = BASE + 0x1000;
+    const LEAVES: u32 = BASE + 0x2000;
+    const PAGE: u32 = BASE + 0x3000;
+
+    // An S-mode hart with Sv32 on and PMP open, over 64 KiB of RAM.
+    fn sv32() -> (MiniRV32IMAState, Vec<u8>) {
+        let mut cpu = MiniRV32IMAState::new(None);
+        cpu.pmp.write_addr(0, u32::MAX);
+        cpu.pmp.write_cfg(0, 0x1f);
+        cpu.satp = crate::mmu::SATP_MODE_SV32 | ROOT >> 12;
+        cpu.extraflags = (cpu.extraflags & !3) | Privilege::Supervisor as u32;
+        (cpu, vec![0u8; 0x10000])
+    }
+
+    fn pte(paddr: u32, flags: u32) -> u32 {
+        (paddr >> 12) << 10 | flags | crate::mmu::PTE_V
+    }
+
+    fn set_pte(image: &mut [u8], table: u32, index: u32, pte: u32) {
+        minirv32_store4(table - BASE + index * 4, pte, image);
+    }
+
+    fn get_pte(image: &[u8], table: u32, index: u32) -> u32 {
+        minirv32_load4(table - BASE + index * 4, image)
+    }
+
+    // Maps the 4 KiB page at 0x40005000 to `paddr` with `flags`.
+    fn map_page(image: &mut [u8], paddr: u32, flags: u32) {
+        set_pte(image, ROOT, 0x100, pte(LEAVES, 0));
+        set_pte(image, LEAVES, 5, pte(paddr, flags));
+    }
+
+    #[test]
+    fn sv32_two_level_walk_sets_a_and_d() {
+        use crate::mmu::{PTE_A, PTE_D, PTE_R, PTE_W};
+        let (mut cpu, mut image) = sv32();
+        map_page(&mut image, PAGE, PTE_R | PTE_W);
+        assert_eq!(
+            cpu.translate(&mut image, 0x40005123, 4, AccessType::Load),
+            Ok(PAGE + 0x123)
+        );
+        assert_eq!(get_pte(&image, LEAVES, 5) & (PTE_A | PTE_D), PTE_A);
+        // The pointer PTE is left alone.
+        assert_eq!(get_pte(&image, ROOT, 0x100), pte(LEAVES, 0));
+
+        assert_eq!(
+            cpu.translate(&mut image, 0x40005ffc, 4, AccessType::Store),
+            Ok(PAGE + 0xffc)
+        );
+        assert_eq!(get_pte(&image, LEAVES, 5) & (PTE_A | PTE_D), PTE_A | PTE_D);
+    }
 
-            self.mepc = pc; //TRICKY: The kernel advances mepc automatically.
-            //CSR( mstatus ) & 8 = MIE, & 0x80 = MPIE
-            // On an interrupt, the system moves current MIE into MPIE
-            self.mstatus = ((self.mstatus & 0x08) << 4) | ((self.extraflags & 3) << 11);
-            pc = self.mtvec - 4;
+    #[test]
+    fn sv32_megapages() {
+        use crate::mmu::PTE_R;
+        let (mut cpu, mut image) = sv32();
+        set_pte(&mut image, ROOT, 0x101, pte(BASE, PTE_R));
+        assert_eq!(
+            cpu.translate(&mut image, 0x40412344, 4, AccessType::Load),
+            Ok(BASE + 0x12344)
+        );
+        // A megapage that isn't 4 MiB aligned.
+        set_pte(&mut image, ROOT, 0x102, pte(BASE + 0x1000, PTE_R));
+        assert_eq!(
+            cpu.translate(&mut image, 0x40800000, 4, AccessType::Load),
+            Err(TrapCause::LoadPageFault)
+        );
+    }
+
+    #[test]
+    fn sv32_sum_and_mxr() {
+        use crate::mmu::{PTE_R, PTE_U, PTE_X};
+        let (mut cpu, mut image) = sv32();
+        map_page(&mut image, PAGE, PTE_R | PTE_X | PTE_U);
+        let va = 0x40005000;
+        // S-mode touches user data only with SUM, and never runs user code.
+        let load = |cpu: &mut MiniRV32IMAState, image: &mut Vec<u8>| {
+            cpu.translate(image, va, 4, AccessType::Load)
+        };
+        assert_eq!(load(&mut cpu, &mut image), Err(TrapCause::LoadPageFault));
+        cpu.mstatus |= MSTATUS_SUM;
+        assert_eq!(load(&mut cpu, &mut image), Ok(PAGE));
+        assert_eq!(
+            cpu.translate(&mut image, va, 4, AccessType::Fetch),
+            Err(TrapCause::InstructionPageFault)
+        );
+        // U-mode can't touch supervisor pages.
+        cpu.extraflags &= !3;
+        assert_eq!(load(&mut cpu, &mut image), Ok(PAGE));
+        map_page(&mut image, PAGE, PTE_R);
+        cpu.tlb.flush();
+        assert_eq!(load(&mut cpu, &mut image), Err(TrapCause::LoadPageFault));
+
+        // MXR makes execute-only pages readable.
+        let (mut cpu, mut image) = sv32();
+        map_page(&mut image, PAGE, PTE_X);
+        assert_eq!(load(&mut cpu, &mut image), Err(TrapCause::LoadPageFault));
+        cpu.mstatus |= MSTATUS_MXR;
+        assert_eq!(load(&mut cpu, &mut image), Ok(PAGE));
+        assert_eq!(
+            cpu.translate(&mut image, va, 4, AccessType::Store),
+            Err(TrapCause::StorePageFault)
+        );
+    }
 
-            // If trapping, always enter machine mode.
-            self.extraflags |= 3;
-            trap = 0;
-            pc += 4;
+    #[test]
+    fn sv32_invalid_ptes_fault() {
+        use crate::mmu::{PTE_R, PTE_V, PTE_W, PTE_X};
+        let va = 0x40005000;
+        let faults = [
+            (AccessType::Fetch, TrapCause::InstructionPageFault),
+            (AccessType::Load, TrapCause::LoadPageFault),
+            (AccessType::Store, TrapCause::StorePageFault),
+        ];
+        for (access, cause) in faults {
+            // Nothing mapped at all.
+            let (mut cpu, mut image) = sv32();
+            assert_eq!(cpu.translate(&mut image, va, 4, access), Err(cause));
+            // A leaf without V.
+            map_page(&mut image, PAGE, PTE_R | PTE_W | PTE_X);
+            set_pte(
+                &mut image,
+                LEAVES,
+                5,
+                pte(PAGE, PTE_R | PTE_W | PTE_X) & !PTE_V,
+            );
+            assert_eq!(cpu.translate(&mut image, va, 4, access), Err(cause));
+            // The reserved W without R.
+            map_page(&mut image, PAGE, PTE_W | PTE_X);
+            assert_eq!(cpu.translate(&mut image, va, 4, access), Err(cause));
+            // A level 0 PTE pointing to yet another table.
+            map_page(&mut image, PAGE, 0);
+            assert_eq!(cpu.translate(&mut image, va, 4, access), Err(cause));
         }
+        // Permissions missing from a valid leaf.
+        let (mut cpu, mut image) = sv32();
+        map_page(&mut image, PAGE, PTE_R);
+        assert_eq!(
+            cpu.translate(&mut image, va, 4, AccessType::Store),
+            Err(TrapCause::StorePageFault)
+        );
+        assert_eq!(
+            cpu.translate(&mut image, va, 4, AccessType::Fetch),
+            Err(TrapCause::InstructionPageFault)
+        );
+    }
 
-        self.pc = pc;
-        print!("PC = {:#010x}\n", self.pc);
-        0
+    #[test]
+    fn sv32_tlb_flush() {
+        use crate::mmu::PTE_R;
+        let (mut cpu, mut image) = sv32();
+        let va = 0x40005010;
+        map_page(&mut image, PAGE, PTE_R);
+        assert_eq!(
+            cpu.translate(&mut image, va, 4, AccessType::Load),
+            Ok(PAGE + 0x10)
+        );
+
+        // The old translation stays cached until satp is written.
+        map_page(&mut image, PAGE + 0x1000, PTE_R);
+        assert_eq!(
+            cpu.translate(&mut image, va, 4, AccessType::Load),
+            Ok(PAGE + 0x10)
+        );
+        let satp = cpu.satp;
+        cpu.csr_access(0x180, Some(CsrWrite::Assign(satp)), true);
+        assert_eq!(
+            cpu.translate(&mut image, va, 4, AccessType::Load),
+            Ok(PAGE + 0x1010)
+        );
+
+        // Or until SFENCE.VMA, run from M-mode here.
+        map_page(&mut image, PAGE, PTE_R);
+        assert_eq!(
+            cpu.translate(&mut image, va, 4, AccessType::Load),
+            Ok(PAGE + 0x1010)
+        );
+        minirv32_store4(0, 0x12000073, &mut image);
+        cpu.extraflags |= 3;
+        cpu.pc = BASE;
+        assert!(matches!(cpu.step(&mut image, 0, 1), StepResult::Retired(1)));
+        cpu.extraflags = (cpu.extraflags & !3) | Privilege::Supervisor as u32;
+        assert_eq!(
+            cpu.translate(&mut image, va, 4, AccessType::Load),
+            Ok(PAGE + 0x10)
+        );
+    }
+
+    fn modes(supervisor: bool, user: bool) -> MiniRV32IMAState {
+        let mut config = MachineConfig::default();
+        config.isa.supervisor = supervisor;
+        config.isa.user = user;
+        MiniRV32IMAState::with_config(config, None)
+    }
+
+    #[test]
+    fn misa_reports_modes_and_custom_extensions() {
+        let mut cpu = modes(true, true);
+        assert_eq!(cpu.misa & (MISA_S | MISA_U | MISA_X), MISA_S | MISA_U);
+        cpu.register_csr(0x7c0, Box::new(|| 0), None);
+        assert_ne!(cpu.misa & MISA_X, 0);
+
+        let mut cpu = modes(false, true);
+        assert_eq!(cpu.misa & (MISA_S | MISA_U | MISA_X), MISA_U);
+        let handler = |_: &CustomInstruction, _: &mut CustomContext| CustomOutcome::Retire {
+            value: None,
+            extra_cycles: 0,
+        };
+        cpu.register_custom_opcode(CustomOpcode::Custom0, Box::new(handler));
+        assert_ne!(cpu.misa & MISA_X, 0);
+
+        assert_eq!(modes(false, false).misa & (MISA_S | MISA_U), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "S-mode requires U-mode")]
+    fn supervisor_requires_user() {
+        modes(true, false);
+    }
+
+    #[test]
+    fn machine_only_hart() {
+        let mut cpu = modes(false, false);
+        // MPP is hardwired to M, MPRV, TW and the S-mode fields to zero.
+        assert_eq!(cpu.mstatus & MSTATUS_MPP, MSTATUS_MPP);
+        cpu.csr_access(0x300, Some(CsrWrite::Assign(!0)), true);
+        cpu.csr_access(0x300, Some(CsrWrite::Clear(MSTATUS_MPP)), true);
+        let mstatus = cpu.csr_access(0x300, None, true).unwrap();
+        assert_eq!(mstatus & MSTATUS_MPP, MSTATUS_MPP);
+        let missing = MSTATUS_MPRV | MSTATUS_TW | MSTATUS_TVM | MSTATUS_TSR | SSTATUS_MASK;
+        assert_eq!(mstatus & missing, 0);
+        for csrno in [0x100, 0x105, 0x180, 0x302, 0x303, 0x306] {
+            assert_eq!(cpu.csr_access(csrno, None, true), None, "CSR {:#x}", csrno);
+        }
+        // mie has no S-mode bits.
+        cpu.csr_access(0x304, Some(CsrWrite::Assign(!0)), true);
+        assert_eq!(cpu.mie & MIP_S_ALL, 0);
+    }
+
+    #[test]
+    fn mret_without_user_stays_in_machine_mode() {
+        let mut config = MachineConfig::default();
+        config.isa.supervisor = false;
+        config.isa.user = false;
+        let (mut cpu, mut image) = boot_with(config, &[0x30200073, 0x30200073]);
+        cpu.mepc = BASE + 4;
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.pc, BASE + 4);
+        assert_eq!(cpu.get_privilege(), Privilege::Machine);
+        // MPP is left at M, not U, so a second MRET stays in M-mode too.
+        assert_eq!(cpu.mstatus & MSTATUS_MPP, MSTATUS_MPP);
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.get_privilege(), Privilege::Machine);
+        assert_eq!(cpu.mstatus & MSTATUS_MPP, MSTATUS_MPP);
+    }
+
+    #[test]
+    fn user_without_supervisor() {
+        let mut cpu = modes(false, true);
+        cpu.csr_access(0x300, Some(CsrWrite::Clear(MSTATUS_MPP)), true);
+        assert_eq!(cpu.mstatus & MSTATUS_MPP, 0);
+        // MPP = S is not a legal value.
+        cpu.csr_access(0x300, Some(CsrWrite::Set(1 << 11)), true);
+        assert_eq!(cpu.mstatus & MSTATUS_MPP, 0);
+        cpu.csr_access(0x300, Some(CsrWrite::Set(MSTATUS_MPRV | MSTATUS_TW)), true);
+        assert_eq!(
+            cpu.mstatus & (MSTATUS_MPRV | MSTATUS_TW),
+            MSTATUS_MPRV | MSTATUS_TW
+        );
+        assert_eq!(cpu.csr_access(0x100, None, true), None);
+        assert!(cpu.csr_access(0x306, None, true).is_some());
+
+        // SRET and SFENCE.VMA are illegal, even in M-mode.
+        for ir in [0x10200073, 0x12000073] {
+            let mut config = MachineConfig::default();
+            config.isa.supervisor = false;
+            let (mut cpu, mut image) = boot_with(config, &[ir]);
+            assert!(matches!(
+                cpu.step(&mut image, 0, 1),
+                StepResult::Fault {
+                    cause: TrapCause::IllegalInstruction,
+                    ..
+                }
+            ));
+        }
+    }
+
+    // custom-0 with rd = x3, rs1 = x1 and rs2 = x2: copies the word at x1 to
+    // x2 and returns it in rd, or raises the fault of the first access.
+    const COPY_WORD: u32 = (2 << 20) | (1 << 15) | (3 << 7) | 0x0b;
+
+    fn copy_word(insn: &CustomInstruction, ctx: &mut CustomContext) -> CustomOutcome {
+        let copied = ctx
+            .load(insn.rs1_val, 4)
+            .and_then(|val| ctx.store(insn.rs2_val, 4, val).map(|()| val));
+        match copied {
+            Ok(val) => CustomOutcome::Retire {
+                value: Some(val),
+                extra_cycles: 0,
+            },
+            Err((cause, mtval)) => CustomOutcome::Trap { cause, mtval },
+        }
+    }
+
+    #[test]
+    fn custom_handler_memory_access() {
+        let (mut cpu, mut image) = boot(&[COPY_WORD]);
+        cpu.register_custom_opcode(CustomOpcode::Custom0, Box::new(copy_word));
+        minirv32_store4(DATA - BASE, 0x12345678, &mut image);
+        cpu.regs[2] = DATA + 4;
+        assert!(matches!(cpu.step(&mut image, 0, 1), StepResult::Retired(1)));
+        assert_eq!(cpu.regs[3], 0x12345678);
+        assert_eq!(minirv32_load4(DATA + 4 - BASE, &image), 0x12345678);
+
+        // Outside RAM and any device.
+        let (mut cpu, mut image) = boot(&[COPY_WORD]);
+        cpu.register_custom_opcode(CustomOpcode::Custom0, Box::new(copy_word));
+        cpu.regs[2] = 0x1000;
+        let result = cpu.step(&mut image, 0, 1);
+        assert!(matches!(
+            result,
+            StepResult::Fault {
+                cause: TrapCause::StoreAccessFault,
+                mtval: 0x1000,
+                ..
+            }
+        ));
+        assert_eq!(cpu.regs[3], 0);
+    }
+
+    #[test]
+    fn custom_handler_memory_is_translated_and_checked() {
+        use crate::mmu::{PTE_R, PTE_X};
+        // U-mode, with the code page identity mapped and the data at
+        // 0x40005000 readable only.
+        let (mut cpu, mut image) = sv32();
+        cpu.extraflags &= !3;
+        set_pte(
+            &mut image,
+            ROOT,
+            0x200,
+            pte(BASE, PTE_R | PTE_X | crate::mmu::PTE_U),
+        );
+        map_page(&mut image, PAGE, PTE_R | crate::mmu::PTE_U);
+        minirv32_store4(0, COPY_WORD, &mut image);
+        minirv32_store4(PAGE - BASE, 0xcafe, &mut image);
+        cpu.register_custom_opcode(CustomOpcode::Custom0, Box::new(copy_word));
+        cpu.pc = BASE;
+        cpu.regs[1] = 0x40005000;
+        cpu.regs[2] = 0x40005004;
+        let result = cpu.step(&mut image, 0, 1);
+        assert!(matches!(
+            result,
+            StepResult::Fault {
+                cause: TrapCause::StorePageFault,
+                mtval: 0x40005004,
+                ..
+            }
+        ));
+
+        // PMP applies to the physical address.
+        let (mut cpu, mut image) = boot(&[COPY_WORD]);
+        cpu.register_custom_opcode(CustomOpcode::Custom0, Box::new(copy_word));
+        // Only the first 256 bytes, the code, are accessible.
+        cpu.pmp.write_addr(0, BASE >> 2 | 0x1f);
+        cpu.pmp.write_cfg(0, 0x1d);
+        cpu.extraflags &= !3;
+        cpu.regs[2] = DATA + 4;
+        let result = cpu.step(&mut image, 0, 1);
+        assert!(matches!(
+            result,
+            StepResult::Fault {
+                cause: TrapCause::LoadAccessFault,
+                mtval: DATA,
+                ..
+            }
+        ));
+    }
+
+    // `lw rd, 0(rs1)`.
+    fn lw(rd: u32, rs1: u32) -> u32 {
+        (rs1 << 15) | (2 << 12) | (rd << 7) | 0x03
+    }
+
+    #[test]
+    fn misaligned_accesses_trap() {
+        let config = MachineConfig {
+            misaligned: MisalignedPolicy::Trap,
+            ..MachineConfig::default()
+        };
+        let (mut cpu, mut image) = boot_with(config, &[lw(3, 1)]);
+        cpu.regs[1] = DATA + 1;
+        cpu.regs[3] = 5;
+        let result = cpu.step(&mut image, 0, 1);
+        assert!(matches!(
+            result,
+            StepResult::Fault {
+                cause: TrapCause::LoadAddressMisaligned,
+                mtval,
+                ..
+            } if mtval == DATA + 1
+        ));
+        assert_eq!(cpu.regs[3], 5);
+
+        let (mut cpu, mut image) = boot_with(config, &[sw(2, 1)]);
+        cpu.regs[1] = DATA + 2;
+        cpu.regs[2] = NEG1;
+        let result = cpu.step(&mut image, 0, 1);
+        assert!(matches!(
+            result,
+            StepResult::Fault {
+                cause: TrapCause::StoreAddressMisaligned,
+                mtval,
+                ..
+            } if mtval == DATA + 2
+        ));
+        assert_eq!(data(&image), 0);
+        assert_eq!(cpu.get_misaligned_count(), 0);
+    }
+
+    #[test]
+    fn misaligned_accesses_emulated() {
+        let (mut cpu, mut image) = boot(&[lw(3, 1), sw(2, 1)]);
+        minirv32_store4(DATA - BASE, 0x44332211, &mut image);
+        minirv32_store4(DATA + 4 - BASE, 0x88776655, &mut image);
+        cpu.regs[1] = DATA + 3;
+        cpu.regs[2] = 0xddccbbaa;
+        cpu.step(&mut image, 0, 2);
+        assert_eq!(cpu.pc, BASE + 8, "unexpected trap");
+        assert_eq!(cpu.regs[3], 0x77665544);
+        assert_eq!(data(&image), 0xaa332211);
+        assert_eq!(minirv32_load4(DATA + 4 - BASE, &image), 0x88ddccbb);
+        assert_eq!(cpu.get_misaligned_count(), 2);
+    }
+
+    #[test]
+    fn misaligned_fault_is_not_partial() {
+        // The last two bytes of the word lie past the end of RAM.
+        let end = BASE + 0x200;
+        let (mut cpu, mut image) = boot(&[sw(2, 1)]);
+        cpu.regs[1] = end - 2;
+        cpu.regs[2] = NEG1;
+        let result = cpu.step(&mut image, 0, 1);
+        assert!(matches!(
+            result,
+            StepResult::Fault {
+                cause: TrapCause::StoreAccessFault,
+                mtval,
+                ..
+            } if mtval == end
+        ));
+        assert_eq!(minirv32_load2(0x1fe, &image), 0);
+        assert_eq!(cpu.get_misaligned_count(), 0);
+
+        let (mut cpu, mut image) = boot(&[lw(3, 1)]);
+        cpu.regs[1] = end - 1;
+        let result = cpu.step(&mut image, 0, 1);
+        assert!(matches!(
+            result,
+            StepResult::Fault {
+                cause: TrapCause::LoadAccessFault,
+                mtval,
+                ..
+            } if mtval == end
+        ));
+        assert_eq!(cpu.get_misaligned_count(), 0);
+    }
+
+    #[test]
+    fn custom_opcode_upper_registers_illegal_on_rv32e() {
+        let called = std::rc::Rc::new(std::cell::Cell::new(false));
+        let flag = called.clone();
+        let handler = move |_: &CustomInstruction, _: &mut CustomContext| {
+            flag.set(true);
+            CustomOutcome::Retire {
+                value: None,
+                extra_cycles: 0,
+            }
+        };
+        // custom-0 with rd = x16.
+        let ir = (16 << 7) | 0x0b;
+        let config = MachineConfig::with_ram_rv32e(BASE, 0x200);
+        let (mut cpu, mut image) = boot_with(config, &[ir]);
+        cpu.register_custom_opcode(CustomOpcode::Custom0, Box::new(handler));
+        assert!(matches!(
+            cpu.step(&mut image, 0, 1),
+            StepResult::Fault {
+                cause: TrapCause::IllegalInstruction,
+                ..
+            }
+        ));
+        assert!(!called.get());
+    }
+
+    #[test]
+    fn custom_handler_cannot_write_upper_registers_on_rv32e() {
+        let handler = |_: &CustomInstruction, ctx: &mut CustomContext| {
+            ctx.set_reg(16, 5);
+            ctx.set_reg(15, 6);
+            CustomOutcome::Retire {
+                value: None,
+                extra_cycles: 0,
+            }
+        };
+        let config = MachineConfig::with_ram_rv32e(BASE, 0x200);
+        let (mut cpu, mut image) = boot_with(config, &[0x0b]);
+        cpu.register_custom_opcode(CustomOpcode::Custom0, Box::new(handler));
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.pc, BASE + 4, "unexpected trap");
+        assert_eq!(cpu.regs[16], 0);
+        assert_eq!(cpu.regs[15], 6);
+    }
+
+    // CLIC tests: interrupts 16 and up are host driven, the handler is at
+    // CLIC_VECTOR and the SHV table at MTVT.
+    const CLIC_VECTOR: u32 = BASE + 0x100;
+    const MTVT: u32 = BASE + 0x400;
+
+    // An M-mode hart in CLIC mode with all level bits in use, so the level
+    // of an interrupt is its control byte, and MIE set. RAM is all NOPs.
+    fn clic() -> (MiniRV32IMAState, Vec<u8>) {
+        let config = MachineConfig {
+            clic_base: Some(crate::clic::CLIC_BASE),
+            ..MachineConfig::default()
+        };
+        let mut cpu = MiniRV32IMAState::with_config(config, None);
+        let image = 0x13u32.to_le_bytes().repeat(0x200);
+        cpu.mtvec = CLIC_VECTOR | MTVEC_MODE_CLIC;
+        cpu.mtvt = MTVT;
+        cpu.mstatus |= MSTATUS_MIE;
+        clic_store(&mut cpu, 0, 8);
+        (cpu, image)
+    }
+
+    fn clic_store(cpu: &mut MiniRV32IMAState, offset: u32, val: u32) {
+        let clic = cpu.clic.as_mut().unwrap();
+        assert!(clic.store(crate::clic::CLIC_BASE + offset, 1, val));
+    }
+
+    // Enables interrupt `id` with control byte `ctl` and raises its line.
+    fn clic_raise(cpu: &mut MiniRV32IMAState, id: u32, ctl: u32) {
+        clic_store(cpu, 0x1000 + id * 4 + 1, 1);
+        clic_store(cpu, 0x1000 + id * 4 + 3, ctl);
+        cpu.raise_clic_irq(id);
+    }
+
+    #[test]
+    fn clic_mintthresh_masks_lower_levels() {
+        let (mut cpu, mut image) = clic();
+        clic_raise(&mut cpu, 16, 0x80);
+        cpu.mintthresh = 0x80;
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.pc, BASE + 4);
+        cpu.mintthresh = 0x7f;
+        cpu.step(&mut image, 0, 1);
+        // Taken and the first handler instruction run.
+        assert_eq!(cpu.pc, CLIC_VECTOR + 4);
+        assert_eq!(cpu.mepc, BASE + 4);
+        assert_eq!(cpu.mcause, MCAUSE_INTERRUPT | 16);
+        assert_eq!(cpu.interrupt_level(), 0x80);
+    }
+
+    #[test]
+    fn clic_preemption_by_higher_level() {
+        let (mut cpu, mut image) = clic();
+        clic_raise(&mut cpu, 16, 0x40);
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.interrupt_level(), 0x40);
+        // The handler re-enables interrupts. One at the same level waits.
+        cpu.mstatus |= MSTATUS_MIE;
+        clic_raise(&mut cpu, 17, 0x40);
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.pc, CLIC_VECTOR + 8);
+        clic_raise(&mut cpu, 18, 0x80);
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.pc, CLIC_VECTOR + 4);
+        assert_eq!(cpu.mepc, CLIC_VECTOR + 8);
+        assert_eq!(
+            cpu.mcause,
+            MCAUSE_INTERRUPT | 0x40 << MCAUSE_MPIL_SHIFT | 18
+        );
+        assert_eq!(cpu.interrupt_level(), 0x80);
+    }
+
+    #[test]
+    fn clic_mnxti_side_effects() {
+        // csrrs x4, mnxti, x0; csrrsi x3, mnxti, MIE; csrrs x5, mnxti, x0
+        let program = [0x34502273, 0x345461f3, 0x345022f3];
+        let (mut cpu, mut image) = clic();
+        for (i, ir) in program.iter().enumerate() {
+            minirv32_store4(i as u32 * 4, *ir, &mut image);
+        }
+        cpu.mstatus &= !MSTATUS_MIE;
+        // An edge-triggered interrupt, left pending while MIE is clear.
+        clic_store(&mut cpu, 0x1000 + 20 * 4 + 2, 2);
+        clic_raise(&mut cpu, 20, 0x60);
+        cpu.step(&mut image, 0, 1);
+        // A read only returns the entry.
+        assert_eq!(cpu.regs[4], MTVT + 4 * 20);
+        assert_eq!(cpu.interrupt_level(), 0);
+        assert_eq!(cpu.get_clic().unwrap().get_pending(), 1 << 20);
+        cpu.step(&mut image, 0, 1);
+        // A write sets MIE and services the interrupt.
+        assert_eq!(cpu.regs[3], MTVT + 4 * 20);
+        assert_ne!(cpu.mstatus & MSTATUS_MIE, 0);
+        assert_eq!(cpu.interrupt_level(), 0x60);
+        assert_eq!(
+            cpu.mcause & (MCAUSE_INTERRUPT | MCAUSE_EXCCODE),
+            MCAUSE_INTERRUPT | 20
+        );
+        assert_eq!(cpu.get_clic().unwrap().get_pending(), 0);
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.pc, BASE + 12);
+        assert_eq!(cpu.regs[5], 0);
+    }
+
+    #[test]
+    fn clic_shv_jumps_through_mtvt() {
+        let (mut cpu, mut image) = clic();
+        minirv32_store4(MTVT - BASE + 4 * 16, BASE + 0x180, &mut image);
+        clic_store(&mut cpu, 0x1000 + 16 * 4 + 2, 1);
+        clic_raise(&mut cpu, 16, 0xff);
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.pc, BASE + 0x184);
+        assert_eq!(cpu.mcause, MCAUSE_INTERRUPT | 16);
+
+        // A table entry that can't be read faults with minhv set.
+        let (mut cpu, mut image) = clic();
+        cpu.mtvt = 0x1000;
+        clic_store(&mut cpu, 0x1000 + 16 * 4 + 2, 1);
+        clic_raise(&mut cpu, 16, 0xff);
+        cpu.step(&mut image, 0, 1);
+        assert_eq!(cpu.pc, CLIC_VECTOR + 4);
+        assert_eq!(
+            cpu.mcause & !MCAUSE_MPIL,
+            MCAUSE_MINHV | TrapCause::InstructionAccessFault.mcause()
+        );
+        assert_eq!(cpu.mepc, 0x1000 + 4 * 16);
+        assert_eq!(cpu.mtval, 0x1000 + 4 * 16);
+    }
+
+    // `addi x5, x5, 1`.
+    const INC: u32 = 0x00128293;
+
+    #[test]
+    fn run_stops_at_ecall() {
+        let (mut cpu, mut image) = boot(&[INC, INC, 0x73]);
+        let result = cpu.run(&mut image, 10);
+        assert_eq!(
+            result,
+            RunResult {
+                executed: 2,
+                remaining: 8,
+                reason: StopReason::Ecall {
+                    privilege: Privilege::Machine
+                },
+            }
+        );
+        assert_eq!(cpu.pc, BASE + 8);
+        assert_eq!(cpu.regs[5], 2);
+    }
+
+    #[test]
+    fn run_stops_at_wfi() {
+        let (mut cpu, mut image) = boot(&[INC, 0x10500073, INC]);
+        let result = cpu.run(&mut image, 10);
+        // WFI retires before the hart sleeps.
+        assert_eq!(result.reason, StopReason::Wfi);
+        assert_eq!((result.executed, result.remaining), (2, 8));
+        assert!(cpu.is_waiting());
+        assert_eq!(cpu.regs[5], 1);
+    }
+
+    #[test]
+    fn run_stops_at_fault() {
+        let (mut cpu, mut image) = boot(&[INC, 0]);
+        let result = cpu.run(&mut image, 10);
+        assert_eq!(
+            result,
+            RunResult {
+                executed: 1,
+                remaining: 9,
+                reason: StopReason::Fault {
+                    cause: TrapCause::IllegalInstruction,
+                    mtval: 0,
+                    pc: BASE + 4,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn run_exhausts_budget() {
+        let (mut cpu, mut image) = boot(&[INC; 0x80]);
+        let result = cpu.run(&mut image, 50);
+        assert_eq!(
+            result,
+            RunResult {
+                executed: 50,
+                remaining: 0,
+                reason: StopReason::BudgetExhausted,
+            }
+        );
+        assert_eq!(cpu.regs[5], 50);
+        assert_eq!(cpu.pc, BASE + 200);
+
+        // Budgets are per call, the instret count carries on.
+        let result = cpu.run(&mut image, 0);
+        assert_eq!((result.executed, result.remaining), (0, 0));
+        assert_eq!(result.reason, StopReason::BudgetExhausted);
+        cpu.run(&mut image, 20);
+        assert_eq!(cpu.regs[5], 70);
+        assert_eq!(cpu.get_instret(), 70);
+    }
+
+    #[test]
+    fn run_is_deterministic() {
+        // 1: addi x5, x5, 1; slli x3, x5, 3; sw x3, 0(x1); j 1b
+        let program = [INC, 0x00329193, 0x0030a023, 0xff5ff06f];
+        let (cpu, image) = boot(&program);
+        let runs: Vec<_> = (0..2)
+            .map(|_| {
+                let (mut cpu, mut image) = (cpu.clone(), image.clone());
+                let result = cpu.run(&mut image, 1001);
+                (result, cpu.get_state(), image)
+            })
+            .collect();
+        assert_eq!(runs[0].0.reason, StopReason::BudgetExhausted);
+        assert_eq!(runs[0].0, runs[1].0);
+        assert_eq!(runs[0].1.pc, runs[1].1.pc);
+        assert_eq!(runs[0].1.regs, runs[1].1.regs);
+        assert_eq!(runs[0].2, runs[1].2);
+        // 250 whole iterations and one more increment.
+        assert_eq!(runs[0].1.regs[5], 251);
+        assert_eq!(data(&runs[0].2), 250 * 8);
     }
 }
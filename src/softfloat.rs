@@ -0,0 +1,668 @@
+// IEEE 754 binary32 arithmetic for the F extension, done with integer
+// operations only so results and exception flags are the same on every host.
+//
+// Values are passed as raw bits. Following RISC-V, every NaN result is the
+// canonical NaN and tininess is detected after rounding.
+
+/// fflags bits, also the layout of the low bits of fcsr.
+pub const FLAG_NX: u32 = 1 << 0;
+pub const FLAG_UF: u32 = 1 << 1;
+pub const FLAG_OF: u32 = 1 << 2;
+pub const FLAG_DZ: u32 = 1 << 3;
+pub const FLAG_NV: u32 = 1 << 4;
+
+pub const CANONICAL_NAN: u32 = 0x7fc00000;
+pub const SIGN: u32 = 0x80000000;
+const INF: u32 = 0x7f800000;
+const MAX_FINITE: u32 = 0x7f7fffff;
+const QUIET: u32 = 0x00400000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoundingMode {
+    NearestEven,
+    TowardZero,
+    Down,
+    Up,
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+    /// Decodes an rm field or frm, `None` for the reserved encodings.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0 => Some(RoundingMode::NearestEven),
+            1 => Some(RoundingMode::TowardZero),
+            2 => Some(RoundingMode::Down),
+            3 => Some(RoundingMode::Up),
+            4 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+fn is_nan(a: u32) -> bool {
+    a & !SIGN > INF
+}
+
+fn is_snan(a: u32) -> bool {
+    is_nan(a) && a & QUIET == 0
+}
+
+fn is_inf(a: u32) -> bool {
+    a & !SIGN == INF
+}
+
+fn is_zero(a: u32) -> bool {
+    a & !SIGN == 0
+}
+
+fn is_neg(a: u32) -> bool {
+    a & SIGN != 0
+}
+
+fn sign_bit(negative: bool) -> u32 {
+    if negative { SIGN } else { 0 }
+}
+
+// The magnitude of a finite non-zero value as `sig * 2^exp`.
+fn unpack(a: u32) -> (u128, i32) {
+    let exp = ((a >> 23) & 0xff) as i32;
+    let frac = (a & 0x7fffff) as u128;
+    if exp == 0 {
+        (frac, -149)
+    } else {
+        (frac | 1 << 23, exp - 150)
+    }
+}
+
+// Exponent of the most significant bit of `sig * 2^exp`.
+fn top_bit(sig: u128, exp: i32) -> i32 {
+    exp + 127 - sig.leading_zeros() as i32
+}
+
+// Result of an operation with a NaN operand. Signaling NaNs are invalid.
+fn nan_result(operands: &[u32], flags: &mut u32) -> u32 {
+    if operands.iter().any(|&op| is_snan(op)) {
+        *flags |= FLAG_NV;
+    }
+    CANONICAL_NAN
+}
+
+fn invalid(flags: &mut u32) -> u32 {
+    *flags |= FLAG_NV;
+    CANONICAL_NAN
+}
+
+// Rounds `sig * 2^exp` to a multiple of `2^lsb_exp`, returning the multiple
+// and whether it is inexact. `sig` must be below 2^127.
+fn round_to(negative: bool, sig: u128, exp: i32, lsb_exp: i32, rm: RoundingMode) -> (u128, bool) {
+    let shift = lsb_exp - exp;
+    if shift <= 0 {
+        return (sig << -shift, false);
+    }
+    let (kept, round, sticky) = if shift > 127 {
+        (0, false, sig != 0)
+    } else {
+        let rest = sig & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        (sig >> shift, rest & half != 0, rest & (half - 1) != 0)
+    };
+    let inexact = round || sticky;
+    let up = match rm {
+        RoundingMode::NearestEven => round && (sticky || kept & 1 != 0),
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => inexact && negative,
+        RoundingMode::Up => inexact && !negative,
+        RoundingMode::NearestMaxMagnitude => round,
+    };
+    (kept + up as u128, inexact)
+}
+
+// Rounds the non-zero value `(-1)^negative * sig * 2^exp` to binary32.
+fn round_pack(negative: bool, sig: u128, exp: i32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    let top = top_bit(sig, exp);
+    // 24 significant bits, fewer once the value is subnormal.
+    let mut lsb_exp = (top - 23).max(-149);
+    let (mut sig_out, inexact) = round_to(negative, sig, exp, lsb_exp, rm);
+    if sig_out == 1 << 24 {
+        // Rounding carried into a new leading bit.
+        sig_out >>= 1;
+        lsb_exp += 1;
+    }
+    let sign = sign_bit(negative);
+
+    if sig_out >= 1 << 23 && lsb_exp + 150 >= 0xff {
+        *flags |= FLAG_OF | FLAG_NX;
+        let to_inf = match rm {
+            RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+            RoundingMode::TowardZero => false,
+            RoundingMode::Down => negative,
+            RoundingMode::Up => !negative,
+        };
+        return sign | if to_inf { INF } else { MAX_FINITE };
+    }
+    if inexact {
+        *flags |= FLAG_NX;
+        // Tiny unless rounding to 24 bits with an unbounded exponent range
+        // reaches the smallest normal number.
+        if top < -126 {
+            let (wide, _) = round_to(negative, sig, exp, top - 23, rm);
+            if top < -127 || wide < 1 << 24 {
+                *flags |= FLAG_UF;
+            }
+        }
+    }
+    if sig_out >= 1 << 23 {
+        sign | ((lsb_exp + 150) as u32) << 23 | (sig_out as u32 & 0x7fffff)
+    } else {
+        sign | sig_out as u32
+    }
+}
+
+// Shifts right, keeping whether any 1 bits were shifted out in bit 0.
+fn shift_right_jam(sig: u128, shift: u32) -> u128 {
+    if shift >= 128 {
+        (sig != 0) as u128
+    } else {
+        (sig >> shift) | ((sig & ((1 << shift) - 1)) != 0) as u128
+    }
+}
+
+// Exact sum of two non-zero values, rounded once. Both are aligned 90 bits
+// below the larger leading bit; an operand far below that can't cancel and
+// only matters as a sticky bit.
+#[allow(clippy::too_many_arguments)]
+fn add_magnitudes(
+    neg_a: bool,
+    sig_a: u128,
+    exp_a: i32,
+    neg_b: bool,
+    sig_b: u128,
+    exp_b: i32,
+    rm: RoundingMode,
+    flags: &mut u32,
+) -> u32 {
+    let base = top_bit(sig_a, exp_a).max(top_bit(sig_b, exp_b)) - 90;
+    let align = |negative: bool, sig: u128, exp: i32| {
+        let aligned = if exp >= base {
+            sig << (exp - base)
+        } else {
+            shift_right_jam(sig, (base - exp) as u32)
+        } as i128;
+        if negative { -aligned } else { aligned }
+    };
+    let total = align(neg_a, sig_a, exp_a) + align(neg_b, sig_b, exp_b);
+    if total == 0 {
+        // An exact zero sum is +0, except when rounding down.
+        return sign_bit(rm == RoundingMode::Down);
+    }
+    round_pack(total < 0, total.unsigned_abs(), base, rm, flags)
+}
+
+// Sum of two zeros: their sign if they agree, otherwise as an exact zero sum.
+fn zero_sum(neg_a: bool, neg_b: bool, rm: RoundingMode) -> u32 {
+    if neg_a == neg_b {
+        sign_bit(neg_a)
+    } else {
+        sign_bit(rm == RoundingMode::Down)
+    }
+}
+
+pub fn add(a: u32, b: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    if is_nan(a) || is_nan(b) {
+        return nan_result(&[a, b], flags);
+    }
+    if is_inf(a) {
+        if is_inf(b) && is_neg(a) != is_neg(b) {
+            return invalid(flags);
+        }
+        return a;
+    }
+    if is_inf(b) {
+        return b;
+    }
+    match (is_zero(a), is_zero(b)) {
+        (true, true) => zero_sum(is_neg(a), is_neg(b), rm),
+        (true, false) => b,
+        (false, true) => a,
+        (false, false) => {
+            let (sig_a, exp_a) = unpack(a);
+            let (sig_b, exp_b) = unpack(b);
+            add_magnitudes(is_neg(a), sig_a, exp_a, is_neg(b), sig_b, exp_b, rm, flags)
+        }
+    }
+}
+
+pub fn sub(a: u32, b: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    add(a, b ^ SIGN, rm, flags)
+}
+
+pub fn mul(a: u32, b: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    if is_nan(a) || is_nan(b) {
+        return nan_result(&[a, b], flags);
+    }
+    let negative = is_neg(a) != is_neg(b);
+    if is_inf(a) || is_inf(b) {
+        if is_zero(a) || is_zero(b) {
+            return invalid(flags);
+        }
+        return sign_bit(negative) | INF;
+    }
+    if is_zero(a) || is_zero(b) {
+        return sign_bit(negative);
+    }
+    let (sig_a, exp_a) = unpack(a);
+    let (sig_b, exp_b) = unpack(b);
+    round_pack(negative, sig_a * sig_b, exp_a + exp_b, rm, flags)
+}
+
+pub fn div(a: u32, b: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    if is_nan(a) || is_nan(b) {
+        return nan_result(&[a, b], flags);
+    }
+    let negative = is_neg(a) != is_neg(b);
+    if is_inf(a) {
+        if is_inf(b) {
+            return invalid(flags);
+        }
+        return sign_bit(negative) | INF;
+    }
+    if is_inf(b) {
+        return sign_bit(negative);
+    }
+    if is_zero(b) {
+        if is_zero(a) {
+            return invalid(flags);
+        }
+        *flags |= FLAG_DZ;
+        return sign_bit(negative) | INF;
+    }
+    if is_zero(a) {
+        return sign_bit(negative);
+    }
+    let (sig_a, exp_a) = unpack(a);
+    let (sig_b, exp_b) = unpack(b);
+    // At least 40 quotient bits, the remainder only matters as a sticky bit.
+    let dividend = sig_a << 64;
+    let quotient = dividend / sig_b;
+    let sticky = (dividend % sig_b != 0) as u128;
+    round_pack(
+        negative,
+        quotient << 1 | sticky,
+        exp_a - exp_b - 65,
+        rm,
+        flags,
+    )
+}
+
+pub fn sqrt(a: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    if is_nan(a) {
+        return nan_result(&[a], flags);
+    }
+    if is_zero(a) {
+        return a; // sqrt(-0) is -0.
+    }
+    if is_neg(a) {
+        return invalid(flags);
+    }
+    if is_inf(a) {
+        return a;
+    }
+    let (mut sig, mut exp) = unpack(a);
+    if exp.rem_euclid(2) != 0 {
+        sig <<= 1;
+        exp -= 1;
+    }
+    // At least 32 root bits, the remainder only matters as a sticky bit.
+    let radicand = sig << 64;
+    let root = radicand.isqrt();
+    let sticky = (root * root != radicand) as u128;
+    round_pack(false, root << 1 | sticky, exp / 2 - 33, rm, flags)
+}
+
+/// `a * b + c` with a single rounding. The FMSUB/FNMADD/FNMSUB forms are this
+/// with the signs of `a` and/or `c` flipped.
+pub fn mul_add(a: u32, b: u32, c: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    if is_nan(a) || is_nan(b) {
+        return nan_result(&[a, b, c], flags);
+    }
+    let neg_prod = is_neg(a) != is_neg(b);
+    let inf_prod = is_inf(a) || is_inf(b);
+    let zero_prod = is_zero(a) || is_zero(b);
+    // inf * 0 is invalid even when c is a quiet NaN.
+    if inf_prod && zero_prod {
+        return invalid(flags);
+    }
+    if is_nan(c) {
+        return nan_result(&[c], flags);
+    }
+    if inf_prod {
+        if is_inf(c) && is_neg(c) != neg_prod {
+            return invalid(flags);
+        }
+        return sign_bit(neg_prod) | INF;
+    }
+    if is_inf(c) {
+        return c;
+    }
+    if zero_prod {
+        if is_zero(c) {
+            return zero_sum(neg_prod, is_neg(c), rm);
+        }
+        return c;
+    }
+    let (sig_a, exp_a) = unpack(a);
+    let (sig_b, exp_b) = unpack(b);
+    let (sig_prod, exp_prod) = (sig_a * sig_b, exp_a + exp_b);
+    if is_zero(c) {
+        return round_pack(neg_prod, sig_prod, exp_prod, rm, flags);
+    }
+    let (sig_c, exp_c) = unpack(c);
+    add_magnitudes(
+        neg_prod,
+        sig_prod,
+        exp_prod,
+        is_neg(c),
+        sig_c,
+        exp_c,
+        rm,
+        flags,
+    )
+}
+
+// Orders non-NaN values with -0 below +0.
+fn total_key(a: u32) -> i64 {
+    if is_neg(a) {
+        -((a & !SIGN) as i64) - 1
+    } else {
+        a as i64
+    }
+}
+
+// FMIN/FMAX: a NaN operand is ignored unless both are NaN, and -0 < +0.
+fn min_max(a: u32, b: u32, max: bool, flags: &mut u32) -> u32 {
+    if is_snan(a) || is_snan(b) {
+        *flags |= FLAG_NV;
+    }
+    match (is_nan(a), is_nan(b)) {
+        (true, true) => CANONICAL_NAN,
+        (true, false) => b,
+        (false, true) => a,
+        (false, false) => {
+            if (total_key(a) < total_key(b)) != max {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+pub fn min(a: u32, b: u32, flags: &mut u32) -> u32 {
+    min_max(a, b, false, flags)
+}
+
+pub fn max(a: u32, b: u32, flags: &mut u32) -> u32 {
+    min_max(a, b, true, flags)
+}
+
+/// FEQ, a quiet comparison: only signaling NaNs are invalid.
+pub fn eq(a: u32, b: u32, flags: &mut u32) -> bool {
+    if is_nan(a) || is_nan(b) {
+        nan_result(&[a, b], flags);
+        return false;
+    }
+    a == b || (is_zero(a) && is_zero(b))
+}
+
+/// FLT, a signaling comparison: any NaN is invalid.
+pub fn lt(a: u32, b: u32, flags: &mut u32) -> bool {
+    if is_nan(a) || is_nan(b) {
+        *flags |= FLAG_NV;
+        return false;
+    }
+    !(is_zero(a) && is_zero(b)) && total_key(a) < total_key(b)
+}
+
+/// FLE, a signaling comparison: any NaN is invalid.
+pub fn le(a: u32, b: u32, flags: &mut u32) -> bool {
+    if is_nan(a) || is_nan(b) {
+        *flags |= FLAG_NV;
+        return false;
+    }
+    (is_zero(a) && is_zero(b)) || total_key(a) <= total_key(b)
+}
+
+// Rounds a finite value to an integer, returning it and whether it is inexact.
+fn round_to_int(a: u32, rm: RoundingMode) -> (i64, bool) {
+    if is_zero(a) {
+        return (0, false);
+    }
+    let (sig, exp) = unpack(a);
+    // Capping the exponent keeps huge values huge (at least 2^39), which is
+    // out of range for every conversion anyway.
+    let (magnitude, inexact) = round_to(is_neg(a), sig, exp.min(16), 0, rm);
+    let magnitude = magnitude as i64;
+    (if is_neg(a) { -magnitude } else { magnitude }, inexact)
+}
+
+// FCVT.W(U).S: out-of-range values and NaNs saturate and are invalid.
+fn to_int(a: u32, rm: RoundingMode, min: i64, max: i64, flags: &mut u32) -> i64 {
+    if is_nan(a) {
+        *flags |= FLAG_NV;
+        return max;
+    }
+    let (val, inexact) = if is_inf(a) {
+        (if is_neg(a) { i64::MIN } else { i64::MAX }, false)
+    } else {
+        round_to_int(a, rm)
+    };
+    if val < min || val > max {
+        *flags |= FLAG_NV;
+        return val.clamp(min, max);
+    }
+    if inexact {
+        *flags |= FLAG_NX;
+    }
+    val
+}
+
+pub fn to_i32(a: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    to_int(a, rm, i32::MIN as i64, i32::MAX as i64, flags) as i32 as u32
+}
+
+pub fn to_u32(a: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    to_int(a, rm, 0, u32::MAX as i64, flags) as u32
+}
+
+fn from_int(negative: bool, magnitude: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    if magnitude == 0 {
+        return 0;
+    }
+    round_pack(negative, magnitude as u128, 0, rm, flags)
+}
+
+pub fn from_i32(val: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    let val = val as i32;
+    from_int(val < 0, val.unsigned_abs(), rm, flags)
+}
+
+pub fn from_u32(val: u32, rm: RoundingMode, flags: &mut u32) -> u32 {
+    from_int(false, val, rm, flags)
+}
+
+/// FCLASS: a one-hot mask, from bit 0 (-inf) to bit 9 (quiet NaN).
+pub fn classify(a: u32) -> u32 {
+    let negative = is_neg(a);
+    let class = if is_nan(a) {
+        if is_snan(a) { 8 } else { 9 }
+    } else if is_inf(a) {
+        if negative { 0 } else { 7 }
+    } else if is_zero(a) {
+        if negative { 3 } else { 4 }
+    } else if a & INF == 0 {
+        if negative { 2 } else { 5 }
+    } else if negative {
+        1
+    } else {
+        6
+    };
+    1 << class
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RNE: RoundingMode = RoundingMode::NearestEven;
+    const RTZ: RoundingMode = RoundingMode::TowardZero;
+    const ONE: u32 = 0x3f800000;
+    const MIN_NORMAL: u32 = 0x00800000;
+
+    #[test]
+    fn rounding_modes() {
+        // 1 + 2^-24 is halfway between 1 and the next float.
+        let half_ulp = 0x33800000;
+        let cases = [
+            (RoundingMode::NearestEven, ONE),
+            (RoundingMode::TowardZero, ONE),
+            (RoundingMode::Down, ONE),
+            (RoundingMode::Up, ONE + 1),
+            (RoundingMode::NearestMaxMagnitude, ONE + 1),
+        ];
+        for (rm, expected) in cases {
+            let mut flags = 0;
+            assert_eq!(add(ONE, half_ulp, rm, &mut flags), expected, "{rm:?}");
+            assert_eq!(flags, FLAG_NX);
+        }
+        let mut flags = 0;
+        assert_eq!(add(ONE, ONE ^ SIGN, RoundingMode::Down, &mut flags), SIGN);
+        assert_eq!(add(ONE, ONE ^ SIGN, RNE, &mut flags), 0);
+        assert_eq!(flags, 0);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut flags = 0;
+        assert_eq!(mul(MAX_FINITE, 0x40000000, RNE, &mut flags), INF);
+        assert_eq!(flags, FLAG_OF | FLAG_NX);
+        flags = 0;
+        assert_eq!(mul(MAX_FINITE, 0x40000000, RTZ, &mut flags), MAX_FINITE);
+        assert_eq!(flags, FLAG_OF | FLAG_NX);
+        assert_eq!(
+            mul(MAX_FINITE | SIGN, 0x40000000, RoundingMode::Up, &mut flags),
+            MAX_FINITE | SIGN
+        );
+    }
+
+    #[test]
+    fn underflow_after_rounding() {
+        let mut flags = 0;
+        // Exact subnormal results don't underflow.
+        assert_eq!(mul(MIN_NORMAL, 0x3f000000, RNE, &mut flags), 0x00400000);
+        assert_eq!(flags, 0);
+        // Rounds up to the smallest normal, but with 24 bits and an unbounded
+        // exponent it would be just below it: tiny.
+        assert_eq!(mul(MIN_NORMAL, 0x3f7fffff, RNE, &mut flags), MIN_NORMAL);
+        assert_eq!(flags, FLAG_UF | FLAG_NX);
+        // Rounds to the smallest normal at 24 bits too: not tiny.
+        flags = 0;
+        assert_eq!(mul(0x3f7ffffa, 0x00800003, RNE, &mut flags), MIN_NORMAL);
+        assert_eq!(flags, FLAG_NX);
+        flags = 0;
+        assert_eq!(mul(MIN_NORMAL, 0x3f7fffff, RTZ, &mut flags), 0x007fffff);
+        assert_eq!(flags, FLAG_UF | FLAG_NX);
+    }
+
+    #[test]
+    fn fused_multiply_add_rounds_once() {
+        // (1 + 2^-23)^2 - (1 + 2^-22) = 2^-46 exactly, lost if the product
+        // were rounded first.
+        let a = ONE + 1;
+        let mut flags = 0;
+        assert_eq!(mul_add(a, a, 0xbf800002, RNE, &mut flags), 0x28800000);
+        assert_eq!(flags, 0);
+        // inf * 0 is invalid even with a quiet NaN addend.
+        assert_eq!(
+            mul_add(INF, 0, CANONICAL_NAN, RNE, &mut flags),
+            CANONICAL_NAN
+        );
+        assert_eq!(flags, FLAG_NV);
+    }
+
+    #[test]
+    fn special_values() {
+        let mut flags = 0;
+        assert_eq!(div(ONE, 0, RNE, &mut flags), INF);
+        assert_eq!(flags, FLAG_DZ);
+        flags = 0;
+        assert_eq!(sqrt(ONE | SIGN, RNE, &mut flags), CANONICAL_NAN);
+        assert_eq!(flags, FLAG_NV);
+        flags = 0;
+        assert_eq!(sqrt(SIGN, RNE, &mut flags), SIGN);
+        assert_eq!(sqrt(0x40800000, RNE, &mut flags), 0x40000000);
+        // Signaling NaNs are invalid, results are always the canonical NaN.
+        assert_eq!(add(0xffc00001, ONE, RNE, &mut flags), CANONICAL_NAN);
+        assert_eq!(flags, 0);
+        assert_eq!(add(0x7f800001, ONE, RNE, &mut flags), CANONICAL_NAN);
+        assert_eq!(flags, FLAG_NV);
+    }
+
+    #[test]
+    fn min_max_and_compare() {
+        let mut flags = 0;
+        assert_eq!(min(SIGN, 0, &mut flags), SIGN);
+        assert_eq!(max(SIGN, 0, &mut flags), 0);
+        assert_eq!(min(CANONICAL_NAN, ONE, &mut flags), ONE);
+        assert_eq!(max(CANONICAL_NAN, CANONICAL_NAN, &mut flags), CANONICAL_NAN);
+        assert!(eq(SIGN, 0, &mut flags));
+        assert!(!lt(SIGN, 0, &mut flags));
+        assert!(!eq(CANONICAL_NAN, ONE, &mut flags));
+        assert_eq!(flags, 0);
+        assert!(!le(CANONICAL_NAN, ONE, &mut flags));
+        assert_eq!(flags, FLAG_NV);
+    }
+
+    #[test]
+    fn integer_conversions() {
+        let mut flags = 0;
+        // 2.5 rounds to even, away from zero with RMM.
+        assert_eq!(to_i32(0x40200000, RNE, &mut flags), 2);
+        assert_eq!(
+            to_i32(0xc0200000, RoundingMode::NearestMaxMagnitude, &mut flags),
+            -3i32 as u32
+        );
+        assert_eq!(flags, FLAG_NX);
+        flags = 0;
+        assert_eq!(to_i32(CANONICAL_NAN, RNE, &mut flags), i32::MAX as u32);
+        assert_eq!(flags, FLAG_NV);
+        flags = 0;
+        assert_eq!(to_i32(0xcf000000, RNE, &mut flags), i32::MIN as u32);
+        assert_eq!(flags, 0);
+        // Negative values that round to zero are fine for the unsigned form.
+        assert_eq!(to_u32(0xbf000000, RTZ, &mut flags), 0);
+        assert_eq!(flags, FLAG_NX);
+        flags = 0;
+        assert_eq!(to_u32(ONE | SIGN, RTZ, &mut flags), 0);
+        assert_eq!(flags, FLAG_NV);
+        flags = 0;
+        // 2^24 + 1 needs 25 bits.
+        assert_eq!(from_i32(0x01000001, RNE, &mut flags), 0x4b800000);
+        assert_eq!(flags, FLAG_NX);
+        assert_eq!(from_u32(u32::MAX, RTZ, &mut flags), 0x4f7fffff);
+    }
+
+    #[test]
+    fn fclass() {
+        assert_eq!(classify(INF | SIGN), 1 << 0);
+        assert_eq!(classify(ONE | SIGN), 1 << 1);
+        assert_eq!(classify(0x80000001), 1 << 2);
+        assert_eq!(classify(SIGN), 1 << 3);
+        assert_eq!(classify(0), 1 << 4);
+        assert_eq!(classify(1), 1 << 5);
+        assert_eq!(classify(ONE), 1 << 6);
+        assert_eq!(classify(INF), 1 << 7);
+        assert_eq!(classify(0x7f800001), 1 << 8);
+        assert_eq!(classify(CANONICAL_NAN), 1 << 9);
+    }
+}
@@ -0,0 +1,133 @@
+// 16550-compatible UART (the "8250" console of Linux and mini-rv32ima) with
+// byte-wide registers. Transmitted bytes go straight to the host, received
+// bytes are polled from it. There is no interrupt line, so guests poll LSR.
+
+use crate::mmio::MmioDevice;
+
+pub const UART_BASE: u32 = 0x10000000;
+pub const UART_SIZE: u32 = 0x100;
+
+// Register offsets. RBR/THR and IER are the divisor latch while LCR.DLAB is set.
+const RBR_THR: u32 = 0;
+const IER: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+const LCR_DLAB: u8 = 1 << 7;
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+// IIR with no interrupt pending, FIFOs are reported as enabled after FCR bit 0.
+const IIR_NO_INT: u8 = 0x01;
+const IIR_FIFO: u8 = 0xc0;
+// DCD, DSR and CTS asserted.
+const MSR_LINES: u8 = 0xb0;
+
+clone_box_fn!(
+    /// Host callback receiving each byte the guest transmits.
+    UartTransmitter: FnMut(u8)
+);
+clone_box_fn!(
+    /// Host callback returning the next received byte, if one is available.
+    UartReceiver: FnMut() -> Option<u8>
+);
+
+pub type UartTxFn = Box<dyn UartTransmitter>;
+pub type UartRxFn = Box<dyn UartReceiver>;
+
+#[derive(Clone)]
+pub struct Uart8250 {
+    tx: UartTxFn,
+    rx: Option<UartRxFn>,
+    rx_byte: Option<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    fifo: bool,
+    divisor: u16,
+}
+
+impl Uart8250 {
+    pub fn new(tx: UartTxFn, rx: Option<UartRxFn>) -> Self {
+        Self {
+            tx,
+            rx,
+            rx_byte: None,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            fifo: false,
+            divisor: 0,
+        }
+    }
+
+    /// A transmit-only UART writing to the host's stdout.
+    pub fn stdout() -> Self {
+        Self::new(
+            Box::new(|byte| {
+                use std::io::Write;
+                let mut out = std::io::stdout();
+                let _ = out.write_all(&[byte]);
+                let _ = out.flush();
+            }),
+            None,
+        )
+    }
+
+    fn poll_rx(&mut self) {
+        if self.rx_byte.is_none()
+            && let Some(rx) = &mut self.rx
+        {
+            self.rx_byte = rx();
+        }
+    }
+}
+
+impl MmioDevice for Uart8250 {
+    fn read8(&mut self, offset: u32) -> Option<u8> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        Some(match offset {
+            RBR_THR if dlab => self.divisor as u8,
+            RBR_THR => {
+                self.poll_rx();
+                self.rx_byte.take().unwrap_or(0)
+            }
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => IIR_NO_INT | if self.fifo { IIR_FIFO } else { 0 },
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.poll_rx();
+                let ready = if self.rx_byte.is_some() { LSR_DR } else { 0 };
+                // Transmission is instantaneous.
+                LSR_THRE | LSR_TEMT | ready
+            }
+            MSR => MSR_LINES,
+            SCR => self.scr,
+            _ => 0,
+        })
+    }
+
+    fn write8(&mut self, offset: u32, val: u8) -> bool {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.divisor = (self.divisor & 0xff00) | val as u16,
+            RBR_THR => (self.tx)(val),
+            IER if dlab => self.divisor = (self.divisor & 0x00ff) | ((val as u16) << 8),
+            IER => self.ier = val & 0x0f,
+            IIR_FCR => self.fifo = val & 1 != 0,
+            LCR => self.lcr = val,
+            MCR => self.mcr = val & 0x1f,
+            SCR => self.scr = val,
+            _ => {} // LSR and MSR are read-only.
+        }
+        true
+    }
+}